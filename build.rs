@@ -0,0 +1,4 @@
+// embed migrations again when they change
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,14 @@
+-- Add up migration script here
+CREATE TABLE books (
+	isbn VARCHAR(255) PRIMARY KEY,
+	title TEXT NOT NULL,
+	descriptions TEXT[] NOT NULL,
+	keywords TEXT[] NOT NULL,
+	creators TEXT[] NOT NULL,
+	publishers TEXT[] NOT NULL,
+	issued_at VARCHAR(255),
+	language VARCHAR(255),
+	annotations TEXT[] NOT NULL,
+	image_url TEXT,
+	cached_at Timestamp NOT NULL
+);
@@ -0,0 +1,16 @@
+-- Add up migration script here
+CREATE TABLE libraries (
+	system_id VARCHAR(255) NOT NULL,
+	ingroup_id VARCHAR(255) NOT NULL,
+	name TEXT NOT NULL,
+	address TEXT NOT NULL,
+	prefecture TEXT NOT NULL,
+	city TEXT NOT NULL,
+	postcode TEXT NOT NULL,
+	tel TEXT NOT NULL,
+	url TEXT NOT NULL,
+	latitude DOUBLE PRECISION NOT NULL,
+	longitude DOUBLE PRECISION NOT NULL,
+	updated_at Timestamp NOT NULL,
+	PRIMARY KEY (system_id, ingroup_id)
+);
@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE favorite_libraries (
+	user_id BIGINT NOT NULL,
+	library_name VARCHAR(255) NOT NULL,
+	created_at Timestamp NOT NULL,
+	PRIMARY KEY (user_id, library_name),
+	FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE bookmarks (
+	user_id BIGINT NOT NULL,
+	isbn VARCHAR(255) NOT NULL,
+	created_at Timestamp NOT NULL,
+	PRIMARY KEY (user_id, isbn),
+	FOREIGN KEY (user_id) REFERENCES users(id)
+);
@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE password_resets (
+	id BIGSERIAL PRIMARY KEY,
+	token VARCHAR(255) UNIQUE NOT NULL,
+	user_id BIGINT NOT NULL,
+	expires_at Timestamp NOT NULL,
+	used_at Timestamp,
+	FOREIGN KEY (user_id) REFERENCES users(id)
+);
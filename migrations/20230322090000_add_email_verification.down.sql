@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE verification_tokens;
+
+ALTER TABLE users DROP COLUMN email_verified;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE verification_tokens (
+	id BIGSERIAL PRIMARY KEY,
+	token VARCHAR(255) UNIQUE NOT NULL,
+	user_id BIGINT NOT NULL,
+	used_at Timestamp,
+	FOREIGN KEY (user_id) REFERENCES users(id)
+);
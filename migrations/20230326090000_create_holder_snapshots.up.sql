@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE holder_snapshots (
+	id BIGSERIAL PRIMARY KEY,
+	reserve_id BIGINT NOT NULL,
+	state VARCHAR(255) NOT NULL,
+	checked_at Timestamp NOT NULL,
+	FOREIGN KEY (reserve_id) REFERENCES reserves(id) ON DELETE CASCADE
+);
+CREATE INDEX holder_snapshots_reserve_id ON holder_snapshots (reserve_id, checked_at);
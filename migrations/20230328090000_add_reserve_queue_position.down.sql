@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE reserves DROP COLUMN queue_position;
@@ -0,0 +1,9 @@
+-- Add up migration script here
+ALTER TABLE reserves ADD COLUMN queue_position INTEGER;
+
+UPDATE reserves SET queue_position = queue.position
+FROM (
+    SELECT id, ROW_NUMBER() OVER (PARTITION BY isbn, library_name ORDER BY staging_at, id) AS position
+    FROM reserves WHERE state <> 'Completed'
+) AS queue
+WHERE reserves.id = queue.id;
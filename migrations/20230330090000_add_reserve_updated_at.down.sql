@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX reserves_user_id_updated_at;
+
+ALTER TABLE reserves DROP COLUMN updated_at;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+ALTER TABLE reserves ADD COLUMN updated_at TIMESTAMP;
+
+UPDATE reserves SET updated_at = GREATEST(staging_at, staged_at, reserved_at, completed_at);
+
+ALTER TABLE reserves ALTER COLUMN updated_at SET NOT NULL;
+
+CREATE INDEX reserves_user_id_updated_at ON reserves (user_id, updated_at);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+ALTER TABLE books DROP COLUMN creator_roles;
+ALTER TABLE books DROP COLUMN creator_names;
+ALTER TABLE books DROP COLUMN series_title;
+ALTER TABLE books DROP COLUMN volume;
+ALTER TABLE books DROP COLUMN source;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE books ADD COLUMN source VARCHAR(255);
+ALTER TABLE books ADD COLUMN volume VARCHAR(255);
+ALTER TABLE books ADD COLUMN series_title TEXT;
+ALTER TABLE books ADD COLUMN creator_names TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE books ADD COLUMN creator_roles TEXT[] NOT NULL DEFAULT '{}';
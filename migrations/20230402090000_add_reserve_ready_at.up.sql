@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE reserves ADD COLUMN ready_at TIMESTAMP;
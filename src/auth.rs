@@ -0,0 +1,277 @@
+use crate::{entity::Entity, error::Error, models::User, validation::body_too_large};
+use actix_web::{
+    dev::Payload,
+    error::{
+        ErrorBadRequest, ErrorInternalServerError, ErrorNotFound, ErrorUnauthorized, InternalError,
+        PayloadError,
+    },
+    http::header::AUTHORIZATION,
+    web::{Bytes, Data},
+    FromRequest, HttpRequest,
+};
+use chrono::{Duration, Utc};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+type E = Error;
+
+// how login tokens are issued and resolved to user
+// session: random token stored in sessions table, revocable
+// jwt: signed token verified in-process, stateless
+#[derive(Debug, Default, Clone)]
+pub enum AuthMode {
+    #[default]
+    Session,
+    Jwt {
+        secret: String,
+        ttl: Duration,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    user_id: i64,
+    exp: i64,
+}
+
+pub fn jwt_issue(secret: &str, user_id: i64, ttl: Duration) -> Result<String, E> {
+    let claims = Claims {
+        user_id,
+        exp: (Utc::now() + ttl).timestamp(),
+    };
+
+    let token = jsonwebtoken::encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .map_err(|err| Error::Internal(err.to_string()))?;
+
+    Ok(token)
+}
+
+pub fn jwt_verify(secret: &str, token: &str) -> Result<i64, E> {
+    let data = jsonwebtoken::decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map_err(|err| Error::Unauthorized(err.to_string()))?;
+
+    Ok(data.claims.user_id)
+}
+
+// empty body for endpoints which require token only
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct NoData {}
+
+// authenticated user resolved from bearer token in authorization header,
+// or token in request body when header is absent
+// remaining body fields are deserialized into data
+#[derive(Debug, Clone)]
+pub struct AuthUser<T = NoData> {
+    pub user: User,
+    pub token: String,
+    pub data: T,
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for AuthUser<T> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let entity = req.app_data::<Data<Entity>>().cloned();
+        let header_token = bearer_token(req);
+        let body = Bytes::from_request(req, payload);
+
+        Box::pin(async move {
+            let entity = entity.ok_or_else(|| ErrorInternalServerError("no entity"))?;
+
+            let body = body
+                .await
+                .map_err(|err| match err.as_error::<PayloadError>() {
+                    Some(PayloadError::Overflow) => {
+                        InternalError::from_response("body too large", body_too_large()).into()
+                    }
+                    _ => err,
+                })?;
+            let mut value: Value = match body.is_empty() {
+                true => Value::Object(Map::new()),
+                false => serde_json::from_slice(&body).map_err(ErrorBadRequest)?,
+            };
+
+            let body_token = value
+                .as_object_mut()
+                .and_then(|object| object.remove("token"))
+                .and_then(|token| token.as_str().map(|text| text.to_string()));
+
+            let token = header_token
+                .or(body_token)
+                .ok_or_else(|| ErrorUnauthorized("missing token"))?;
+
+            let user = entity
+                .user_get(&token)
+                .await
+                .map_err(|_| ErrorUnauthorized("invalid token"))?;
+
+            let data = serde_json::from_value(value).map_err(ErrorBadRequest)?;
+
+            Ok(AuthUser { user, token, data })
+        })
+    }
+}
+
+// operator token guarding admin endpoints, admin endpoints are absent when not configured
+#[derive(Debug, Default, Clone)]
+pub struct AdminToken(pub Option<String>);
+
+// request authenticated by bearer token equal to admin token
+#[derive(Debug, Clone)]
+pub struct AdminAuth;
+
+impl FromRequest for AdminAuth {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let Some(admin_token) = req
+            .app_data::<Data<AdminToken>>()
+            .and_then(|admin_token| admin_token.0.clone())
+        else {
+            return ready(Err(ErrorNotFound("not found")));
+        };
+
+        match bearer_token(req) {
+            Some(token) if token_eq(&token, &admin_token) => ready(Ok(AdminAuth)),
+            _ => ready(Err(ErrorUnauthorized("invalid admin token"))),
+        }
+    }
+}
+
+// compare without early return so timing does not reveal matched prefix
+fn token_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    if !scheme.eq_ignore_ascii_case("bearer") || token.trim().is_empty() {
+        return None;
+    }
+
+    Some(token.trim().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{bearer_token, jwt_issue, jwt_verify, token_eq, AuthUser};
+    use crate::entity::Entity;
+    use actix_web::{
+        http::{header::AUTHORIZATION, StatusCode},
+        test::{call_service, init_service, TestRequest},
+        web::{post, Data},
+        App, HttpResponse,
+    };
+    use chrono::Duration;
+    use std::env;
+
+    #[test]
+    fn test_jwt() {
+        let token = jwt_issue("secret", 42, Duration::hours(1)).unwrap();
+        assert_eq!(jwt_verify("secret", &token).unwrap(), 42);
+
+        // signed by another secret
+        assert!(jwt_verify("other", &token).is_err());
+
+        // payload modified after signing
+        let mut parts: Vec<_> = token.split('.').map(|part| part.to_string()).collect();
+        parts[1] = parts[1].chars().rev().collect();
+        assert!(jwt_verify("secret", &parts.join(".")).is_err());
+
+        // expired beyond leeway
+        let token = jwt_issue("secret", 42, Duration::hours(-1)).unwrap();
+        assert!(jwt_verify("secret", &token).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_auth_user() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let entity = Entity::new(&appkey).await.unwrap();
+        let token = entity
+            .user_login("alice@example2.com", "alice")
+            .await
+            .unwrap();
+
+        let app = init_service(App::new().app_data(Data::new(entity)).route(
+            "/",
+            post().to(|user: AuthUser| async move { HttpResponse::Ok().body(user.user.email) }),
+        ))
+        .await;
+
+        let req = TestRequest::post().uri("/").to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = TestRequest::post()
+            .uri("/")
+            .set_json(serde_json::json!({ "token": "invalid" }))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = TestRequest::post()
+            .uri("/")
+            .set_json(serde_json::json!({ "token": token }))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header((AUTHORIZATION, format!("Bearer {token}")))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // header takes precedence over body
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header((AUTHORIZATION, format!("Bearer {token}")))
+            .set_json(serde_json::json!({ "token": "invalid" }))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_token_eq() {
+        assert!(token_eq("secret", "secret"));
+        assert!(!token_eq("secret", "secreT"));
+        assert!(!token_eq("secret", "secret2"));
+        assert!(!token_eq("", "secret"));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer abc"))
+            .to_http_request();
+        assert_eq!(bearer_token(&req).as_deref(), Some("abc"));
+
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Basic abc"))
+            .to_http_request();
+        assert_eq!(bearer_token(&req), None);
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(bearer_token(&req), None);
+    }
+}
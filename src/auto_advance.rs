@@ -0,0 +1,403 @@
+use crate::{
+    calil_api::CalilAppState,
+    entity::Entity,
+    error::Error,
+    models::{HolderState, Reserve},
+};
+use std::{str::FromStr, time::Duration};
+
+type E = Error;
+
+// reserve states which can be advanced, reserved one becomes ready
+// and ready or completed ones are left to the user
+const STATES: [&str; 3] = ["Staging", "Staged", "Reserved"];
+
+// advance reserve in state when holder state at its library changes to current,
+// from previous if given or from any other state, e.g. a borrowed copy returned
+// written as Reserved:Borrowed>Exists or Staged:*>Reservable
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdvanceRule {
+    pub state: String,
+    pub previous: Option<HolderState>,
+    pub current: HolderState,
+}
+
+impl AdvanceRule {
+    // first check only sets the baseline, a change is needed to advance
+    pub fn matches(
+        &self,
+        state: &str,
+        previous: Option<&HolderState>,
+        current: &HolderState,
+    ) -> bool {
+        let Some(previous) = previous else {
+            return false;
+        };
+
+        self.state == state
+            && previous != current
+            && self.current == *current
+            && self.previous.as_ref().is_none_or(|rule| rule == previous)
+    }
+}
+
+impl FromStr for AdvanceRule {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Validation(format!("invalid advance rule: {text:?}"));
+
+        let (state, change) = text.trim().split_once(':').ok_or_else(invalid)?;
+        let (previous, current) = change.split_once('>').ok_or_else(invalid)?;
+
+        let state = STATES
+            .into_iter()
+            .find(|name| name.eq_ignore_ascii_case(state.trim()))
+            .ok_or_else(invalid)?;
+        let previous = match previous.trim() {
+            "*" => None,
+            previous => Some(previous.parse()?),
+        };
+
+        Ok(Self {
+            state: state.to_string(),
+            previous,
+            current: current.parse()?,
+        })
+    }
+}
+
+// comma separated rules, empty text gives no rule
+pub fn rules_parse(text: &str) -> Result<Vec<AdvanceRule>, E> {
+    text.split(',')
+        .filter(|text| !text.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// copy which was lent out is back on the shelf of the library
+pub fn default_rules() -> Vec<AdvanceRule> {
+    ["Reserved:Borrowed>Exists", "Reserved:Borrowed>Reservable"]
+        .into_iter()
+        .filter_map(|text| text.parse().ok())
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct AutoAdvance {
+    entity: Entity,
+    calil: CalilAppState,
+    rules: Vec<AdvanceRule>,
+}
+
+impl AutoAdvance {
+    pub fn new(entity: Entity, calil: CalilAppState) -> Self {
+        Self {
+            entity,
+            calil,
+            rules: default_rules(),
+        }
+    }
+
+    pub fn with_rules(self, rules: Vec<AdvanceRule>) -> Self {
+        Self { rules, ..self }
+    }
+
+    // check every reserve some rule applies to, record its holder state and
+    // advance matched ones, return the number of advanced reserves
+    // failure of one reserve is logged and does not stop the others
+    pub async fn run_once(&self) -> Result<usize, E> {
+        let mut states: Vec<_> = self.rules.iter().map(|rule| rule.state.clone()).collect();
+        states.sort();
+        states.dedup();
+
+        let reserves = self.entity.reserve_query_states(&states).await?;
+        let pairs: Vec<_> = reserves
+            .iter()
+            .map(|reserve| (reserve.isbn.as_str(), reserve.library_name.as_str()))
+            .collect();
+        let holder_states = self.calil.holder_states(&pairs).await;
+
+        let mut advanced = 0;
+        for (reserve, current) in reserves.iter().zip(holder_states) {
+            match self.advance(reserve, &current).await {
+                Ok(true) => advanced += 1,
+                Ok(false) => {}
+                Err(err) => eprintln!("warn: auto advance: reserve {}: {err}", reserve.id),
+            }
+        }
+
+        Ok(advanced)
+    }
+
+    // snapshot is recorded after advancing, so failed one is retried next time
+    async fn advance(&self, reserve: &Reserve, current: &HolderState) -> Result<bool, E> {
+        // failed check tells nothing about the book
+        if *current == HolderState::Unknown {
+            return Ok(false);
+        }
+
+        let previous = self.entity.holder_snapshot_latest(reserve.id).await?;
+
+        // a copy coming back serves the first of the queue only
+        let matched = reserve.queue_position == Some(1)
+            && self
+                .rules
+                .iter()
+                .any(|rule| rule.matches(&reserve.state, previous.as_ref(), current));
+
+        if matched {
+            // reserved copy on the shelf waits for pickup, it is not completed yet
+            match reserve.state.as_str() {
+                "Reserved" => {
+                    self.entity
+                        .reserve_ready(reserve.user_id, reserve.id)
+                        .await?
+                }
+                _ => {
+                    self.entity
+                        .reserve_advance(reserve.user_id, reserve.id)
+                        .await?
+                }
+            };
+        }
+
+        self.entity.holder_snapshot_add(reserve.id, current).await?;
+
+        Ok(matched)
+    }
+
+    // run at every interval in background, failed run is retried next time
+    pub fn spawn(self, interval: Duration) {
+        actix_web::rt::spawn(async move {
+            let mut ticker = actix_web::rt::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(err) = self.run_once().await {
+                    eprintln!("warn: auto advance: {err}");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{default_rules, rules_parse, AdvanceRule, AutoAdvance};
+    use crate::{calil_api::CalilAppState, entity::Entity, models::HolderState};
+    use actix_web::{web, App, HttpResponse};
+    use std::{
+        env,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[test]
+    fn test_rule_parse() {
+        let rule: AdvanceRule = "reserved:Borrowed>貸出可".parse().unwrap();
+        assert_eq!(
+            rule,
+            AdvanceRule {
+                state: "Reserved".to_string(),
+                previous: Some(HolderState::Borrowed),
+                current: HolderState::Reservable,
+            }
+        );
+
+        let rules = rules_parse("Staged:*>Exists, ").unwrap();
+        assert_eq!(rules.len(), 1);
+        assert!(rules[0].previous.is_none());
+
+        assert!(rules_parse("").unwrap().is_empty());
+        for text in [
+            "Completed:*>Exists",
+            "Reserved:Borrowed",
+            "Reserved",
+            "Reserved:Lost>Exists",
+        ] {
+            assert!(text.parse::<AdvanceRule>().is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_rule_matches() {
+        let rules = default_rules();
+        let advance = |state: &str, previous: Option<HolderState>, current: HolderState| {
+            rules
+                .iter()
+                .any(|rule| rule.matches(state, previous.as_ref(), &current))
+        };
+
+        // borrowed copy is returned
+        assert!(advance(
+            "Reserved",
+            Some(HolderState::Borrowed),
+            HolderState::Exists
+        ));
+        assert!(advance(
+            "Reserved",
+            Some(HolderState::Borrowed),
+            HolderState::Reservable
+        ));
+
+        // no change, first check, other state or other transition
+        assert!(!advance(
+            "Reserved",
+            Some(HolderState::Borrowed),
+            HolderState::Borrowed
+        ));
+        assert!(!advance("Reserved", None, HolderState::Exists));
+        assert!(!advance(
+            "Staged",
+            Some(HolderState::Borrowed),
+            HolderState::Exists
+        ));
+        assert!(!advance(
+            "Reserved",
+            Some(HolderState::Nothing),
+            HolderState::Exists
+        ));
+
+        // wildcard accepts change from any state
+        let rule: AdvanceRule = "Staged:*>Reservable".parse().unwrap();
+        assert!(rule.matches(
+            "Staged",
+            Some(&HolderState::Nothing),
+            &HolderState::Reservable
+        ));
+        assert!(!rule.matches(
+            "Staged",
+            Some(&HolderState::Reservable),
+            &HolderState::Reservable
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_run_once() {
+        // library of this run only, so the reserve is the first of the queue
+        let system_id = format!("Auto_Lib_{}", rand::random::<u32>());
+        let library_name = format!("自動図書館{system_id}");
+        let libraries = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<Libraries>
+<Library>
+<systemid>{system_id}</systemid>
+<libkey>本館</libkey>
+<formal>{library_name}</formal>
+<url_pc>https://example.com/library</url_pc>
+<address>富山県射水市</address>
+<pref>富山県</pref>
+<city>射水市</city>
+<post>939-0398</post>
+<tel>0766-00-0000</tel>
+<geocode>137.0958753,36.7077262</geocode>
+</Library>
+</Libraries>"#
+        );
+        let check = |state: &str| {
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<result>
+<session>session-id</session>
+<continue>0</continue>
+<books>
+<book isbn="9784834000825" calilurl="">
+<system systemid="{system_id}">
+<status>OK</status>
+<reserveurl></reserveurl>
+<libkeys><libkey name="本館">{state}</libkey></libkeys>
+</system>
+</book>
+</books>
+</result>"#
+            )
+        };
+        let (borrowed, returned) = (check("貸出中"), check("貸出可"));
+
+        let is_returned = Arc::new(AtomicBool::new(false));
+        let flag = is_returned.clone();
+        let srv = actix_test::start(move || {
+            let libraries = libraries.clone();
+            let (borrowed, returned, flag) = (borrowed.clone(), returned.clone(), flag.clone());
+            App::new()
+                .route(
+                    "/library",
+                    web::get().to(move || {
+                        let body = libraries.clone();
+                        async move {
+                            HttpResponse::Ok()
+                                .content_type("application/xml")
+                                .body(body)
+                        }
+                    }),
+                )
+                .route(
+                    "/check",
+                    web::get().to(move || {
+                        let body = if flag.load(Ordering::SeqCst) {
+                            returned.clone()
+                        } else {
+                            borrowed.clone()
+                        };
+                        async move {
+                            HttpResponse::Ok()
+                                .content_type("application/xml")
+                                .body(body)
+                        }
+                    }),
+                )
+        });
+        let calil = CalilAppState::new("appkey")
+            .with_base_url(&format!("http://{}", srv.addr()))
+            .with_holder_ttl(Duration::ZERO);
+        calil.pull_data().await.unwrap();
+
+        let entity = Entity::new(&env::var("DATABASE_URL").unwrap())
+            .await
+            .unwrap();
+        let email = format!("advance-{}@example2.com", rand::random::<u32>());
+        entity
+            .user_create(&email, "password", "アドバンス", "日本")
+            .await
+            .unwrap();
+        let token = entity.user_login(&email, "password").await.unwrap();
+        let user = entity.user_get(&token).await.unwrap();
+        entity
+            .reserve_create(user.id, "9784834000825", &library_name, None)
+            .await
+            .unwrap();
+        let reserve = entity.reserve_query_all(user.id).await.unwrap().remove(0);
+        for _ in 0..2 {
+            entity.reserve_advance(user.id, reserve.id).await.unwrap();
+        }
+
+        let auto_advance = AutoAdvance::new(entity.clone(), calil);
+        let state = || async { entity.reserve_get(user.id, reserve.id).await.unwrap().state };
+
+        // first check only sets the baseline
+        auto_advance.run_once().await.unwrap();
+        assert_eq!(state().await, "Reserved");
+
+        // still borrowed, nothing changes
+        auto_advance.run_once().await.unwrap();
+        assert_eq!(state().await, "Reserved");
+
+        // borrowed copy is returned
+        is_returned.store(true, Ordering::SeqCst);
+        assert!(auto_advance.run_once().await.unwrap() >= 1);
+        assert_eq!(state().await, "Ready");
+        assert!(entity
+            .reserve_get(user.id, reserve.id)
+            .await
+            .unwrap()
+            .ready_at
+            .is_some());
+
+        // ready one is left to the user
+        auto_advance.run_once().await.unwrap();
+        assert_eq!(state().await, "Ready");
+    }
+}
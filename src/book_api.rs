@@ -0,0 +1,981 @@
+use crate::{
+    error::Error,
+    google_api::GoogleAppState,
+    isbn,
+    models::{self, fold},
+    ndl_api::NdlAppState,
+    openbd_api::OpenBdAppState,
+    rakuten_api::RakutenAppState,
+    upstream::{self, Agent, BreakerStatus, UpstreamRequest},
+};
+use awc::ClientRequest;
+use futures::{stream, StreamExt};
+use std::collections::HashMap;
+
+type E = Error;
+
+// concurrent upstream fetch per bulk request
+const BULK_CONCURRENCY: usize = 8;
+
+// pseudo backend which searches every full text backend at once
+pub const AGGREGATE: &str = "all";
+
+const AGGREGATE_BACKENDS: [&str; 3] = ["ndl", "google", "rakuten"];
+
+// backends paginate independently, so aggregated page n fetches first (n + 1) * page_size
+// items from each backend and slices the merged list, deep page is refused
+const AGGREGATE_MAX_WINDOW: u32 = 200;
+
+// relevance weight of query term found in each field of aggregated result
+const TITLE_WEIGHT: u32 = 6;
+const CREATOR_WEIGHT: u32 = 3;
+const DESCRIPTION_WEIGHT: u32 = 1;
+
+// backends tried in order when image of the requested backend is missing
+const THUMBNAIL_BACKENDS: [&str; 4] = ["google", "rakuten", "openbd", "ndl"];
+
+// name and label of every single backend
+const BACKENDS: [(&str, &str); 4] = [
+    ("ndl", "NDL Search"),
+    ("google", "Google Books"),
+    ("rakuten", "Rakuten Books"),
+    ("openbd", "openBD"),
+];
+
+// dispatch book search to backend by name
+#[derive(Debug, Clone)]
+pub struct BookAppState {
+    ndl: NdlAppState,
+    google: GoogleAppState,
+    rakuten: RakutenAppState,
+    openbd: OpenBdAppState,
+    verify_thumbnail: bool,
+    agent: Agent,
+    default_backend: String,
+    fallback_backends: Vec<String>,
+    // backends which have their api key, others are refused
+    backends: Vec<String>,
+    reserve_check: bool,
+    explain: bool,
+}
+
+impl Default for BookAppState {
+    fn default() -> Self {
+        Self::new(
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        )
+    }
+}
+
+impl BookAppState {
+    pub fn new(
+        ndl: NdlAppState,
+        google: GoogleAppState,
+        rakuten: RakutenAppState,
+        openbd: OpenBdAppState,
+    ) -> Self {
+        Self {
+            ndl,
+            google,
+            rakuten,
+            openbd,
+            verify_thumbnail: false,
+            agent: Default::default(),
+            default_backend: AGGREGATE.to_string(),
+            fallback_backends: AGGREGATE_BACKENDS.map(String::from).to_vec(),
+            backends: BACKENDS.map(|(name, _)| name.to_string()).to_vec(),
+            reserve_check: false,
+            explain: false,
+        }
+    }
+
+    // backend to look up book by isbn when client does not choose one
+    pub fn with_default_backend(self, default_backend: &str) -> Self {
+        Self {
+            default_backend: default_backend.to_string(),
+            ..self
+        }
+    }
+
+    // backends tried in order by isbn lookup without explicit backend,
+    // after the default backend
+    pub fn with_fallback_backends(self, fallback_backends: &[&str]) -> Self {
+        Self {
+            fallback_backends: fallback_backends
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            ..self
+        }
+    }
+
+    // only these backends are used, e.g. when api key of others is missing
+    pub fn with_backends(self, backends: &[&str]) -> Self {
+        Self {
+            backends: backends.iter().map(|name| name.to_string()).collect(),
+            ..self
+        }
+    }
+
+    // confirm isbn of reserve is a real book, which costs a lookup per reserve
+    pub fn with_reserve_check(self, reserve_check: bool) -> Self {
+        Self {
+            reserve_check,
+            ..self
+        }
+    }
+
+    // allow client to see upstream requests, api keys are masked but
+    // query building is exposed, so not for production
+    pub fn with_explain(self, explain: bool) -> Self {
+        Self { explain, ..self }
+    }
+
+    pub fn explain(&self) -> bool {
+        self.explain
+    }
+
+    pub fn default_backend(&self) -> &str {
+        &self.default_backend
+    }
+
+    pub fn reserve_check(&self) -> bool {
+        self.reserve_check
+    }
+
+    // check image url of book get by head request and fall back to other backends,
+    // which costs extra requests per book
+    pub fn with_verify_thumbnail(self, verify_thumbnail: bool) -> Self {
+        Self {
+            verify_thumbnail,
+            ..self
+        }
+    }
+
+    // used by thumbnail check, backends have their own
+    pub fn with_agent(self, agent: Agent) -> Self {
+        Self { agent, ..self }
+    }
+
+    pub fn breaker_status(&self) -> Vec<(&'static str, BreakerStatus)> {
+        vec![
+            ("ndl", self.ndl.breaker_status()),
+            ("google", self.google.breaker_status()),
+            ("rakuten", self.rakuten.breaker_status()),
+            ("openbd", self.openbd.breaker_status()),
+        ]
+        .into_iter()
+        .filter(|(backend, _)| self.is_configured(backend))
+        .collect()
+    }
+
+    pub fn has_backend(&self, backend: &str) -> bool {
+        backend == AGGREGATE || BACKENDS.iter().any(|(name, _)| *name == backend)
+    }
+
+    // configured backends with their label, aggregate when it has any backend to ask
+    pub fn backend_labels(&self) -> Vec<(&'static str, &'static str)> {
+        let mut labels: Vec<_> = BACKENDS
+            .into_iter()
+            .filter(|(name, _)| self.is_configured(name))
+            .collect();
+        if self.aggregate_backends().next().is_some() {
+            labels.push((AGGREGATE, "All backends"));
+        }
+        labels
+    }
+
+    // aggregate uses whichever backends are configured
+    pub fn is_configured(&self, backend: &str) -> bool {
+        backend == AGGREGATE || self.backends.iter().any(|name| name == backend)
+    }
+
+    pub fn backends(&self) -> Vec<&str> {
+        self.backends.iter().map(String::as_str).collect()
+    }
+
+    // explicitly requested backend without api key
+    fn configured_check(&self, backend: &str) -> Result<(), E> {
+        match self.is_configured(backend) {
+            true => Ok(()),
+            false => Err(Error::Validation("backend not configured".to_string())),
+        }
+    }
+
+    fn aggregate_backends(&self) -> impl Iterator<Item = &&str> {
+        AGGREGATE_BACKENDS
+            .iter()
+            .filter(|backend| self.is_configured(backend))
+    }
+
+    pub fn has_field_search(&self, backend: &str) -> bool {
+        matches!(backend, "ndl")
+    }
+
+    // filter which is a valid isbn is looked up exactly like search box,
+    // otherwise search anywhere
+    // lang restrict is an iso 639-1 code, only google restricts results by it
+    pub async fn book_query(
+        &self,
+        backend: &str,
+        any: &str,
+        fields: &models::BookFields,
+        lang_restrict: Option<&str>,
+        page_size: u32,
+        page: u32,
+    ) -> Result<models::BookChunk, E> {
+        if let Some(isbn) = isbn::normalize(any) {
+            let item = self.book_get(backend, &isbn).await?;
+            let items = match page {
+                0 if page_size > 0 => vec![item],
+                _ => vec![],
+            };
+
+            return Ok(models::BookChunk {
+                items,
+                total_count: 1,
+                page_info: models::PageInfo::new(page, page_size, 1),
+            });
+        }
+
+        if !fields.is_empty() && !self.has_field_search(backend) {
+            return Err(Error::Validation(
+                "field search is not supported".to_string(),
+            ));
+        }
+
+        match backend {
+            AGGREGATE => {
+                self.book_query_aggregate(any, lang_restrict, page_size, page)
+                    .await
+            }
+            _ => {
+                self.backend_query(backend, any, fields, lang_restrict, page_size, page)
+                    .await
+            }
+        }
+    }
+
+    // merge results of every backend, duplicated isbn is kept only at first appearance
+    // total count is estimated from the duplication ratio in fetched window
+    async fn book_query_aggregate(
+        &self,
+        any: &str,
+        lang_restrict: Option<&str>,
+        page_size: u32,
+        page: u32,
+    ) -> Result<models::BookChunk, E> {
+        let window = page_size.saturating_mul(page.saturating_add(1));
+        if window > AGGREGATE_MAX_WINDOW {
+            return Err(Error::Validation(
+                "page is too deep for aggregated search".to_string(),
+            ));
+        }
+
+        let fields = models::BookFields::default();
+        let results =
+            futures::future::join_all(self.aggregate_backends().map(|backend| {
+                self.backend_query(backend, any, &fields, lang_restrict, window, 0)
+            }))
+            .await;
+
+        // failed backend is skipped unless every backend failed
+        let mut chunks = vec![];
+        let mut last_err = None;
+        for result in results {
+            match result {
+                Ok(chunk) => chunks.push(chunk),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        if let (true, Some(err)) = (chunks.is_empty(), last_err) {
+            return Err(err);
+        }
+
+        Ok(merge_window(chunks, any, page_size, page))
+    }
+
+    // requests which book query sends to search backends,
+    // isbn lookup and openbd coverage search send no search request
+    pub fn book_query_explain(
+        &self,
+        backend: &str,
+        any: &str,
+        fields: &models::BookFields,
+        lang_restrict: Option<&str>,
+        page_size: u32,
+        page: u32,
+    ) -> Result<Vec<UpstreamRequest>, E> {
+        if isbn::normalize(any).is_some() {
+            return Ok(vec![]);
+        }
+
+        let requests = match backend {
+            AGGREGATE => {
+                let window = page_size.saturating_mul(page.saturating_add(1));
+                let fields = models::BookFields::default();
+                self.aggregate_backends()
+                    .map(|backend| {
+                        self.backend_query_request(backend, any, &fields, lang_restrict, window, 0)
+                    })
+                    .collect::<Result<Vec<_>, E>>()?
+            }
+            _ => vec![self.backend_query_request(
+                backend,
+                any,
+                fields,
+                lang_restrict,
+                page_size,
+                page,
+            )?],
+        };
+
+        Ok(requests
+            .iter()
+            .flatten()
+            .map(UpstreamRequest::new)
+            .collect())
+    }
+
+    fn backend_query_request(
+        &self,
+        backend: &str,
+        any: &str,
+        fields: &models::BookFields,
+        lang_restrict: Option<&str>,
+        page_size: u32,
+        page: u32,
+    ) -> Result<Option<ClientRequest>, E> {
+        self.configured_check(backend)?;
+
+        let request = match backend {
+            "ndl" => self.ndl.book_query_request(any, fields, page_size, page)?,
+            "google" => self
+                .google
+                .book_query_request(any, lang_restrict, page_size, page)?,
+            "rakuten" => self.rakuten.book_query_request(any, page_size, page)?,
+            "openbd" => return Ok(None),
+            _ => return Err(Error::Validation("invalid backend".to_string())),
+        };
+        Ok(Some(request))
+    }
+
+    async fn backend_query(
+        &self,
+        backend: &str,
+        any: &str,
+        fields: &models::BookFields,
+        lang_restrict: Option<&str>,
+        page_size: u32,
+        page: u32,
+    ) -> Result<models::BookChunk, E> {
+        self.configured_check(backend)?;
+
+        match backend {
+            "ndl" => {
+                self.ndl
+                    .book_query_fields(any, fields, page_size, page)
+                    .await
+            }
+            "google" => {
+                self.google
+                    .book_query(any, lang_restrict, page_size, page)
+                    .await
+            }
+            "rakuten" => self.rakuten.book_query(any, page_size, page).await,
+            "openbd" => self.openbd.book_query(any, page_size, page).await,
+            _ => Err(Error::Validation("invalid backend".to_string())),
+        }
+    }
+
+    // explicitly requested backend is the only one tried
+    pub async fn book_get(&self, backend: &str, isbn: &str) -> Result<models::Book, E> {
+        match backend {
+            AGGREGATE => {
+                let backends: Vec<_> = self
+                    .fallback_backends
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|backend| self.is_configured(backend))
+                    .collect();
+                self.book_get_chain(&backends, isbn).await
+            }
+            _ => self.book_get_chain(&[backend], isbn).await,
+        }
+    }
+
+    // default backend first, then fallback backends until one finds the book
+    pub async fn book_get_default(&self, isbn: &str) -> Result<models::Book, E> {
+        let backends: Vec<_> = [self.default_backend.as_str()]
+            .into_iter()
+            .filter(|backend| *backend != AGGREGATE)
+            .chain(
+                self.fallback_backends
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|backend| *backend != self.default_backend),
+            )
+            .filter(|backend| self.is_configured(backend))
+            .collect();
+        self.book_get_chain(&backends, isbn).await
+    }
+
+    // first backend which finds the book, tagged with the backend
+    // only a missing book moves on, other failures are not hidden by later backends
+    async fn book_get_chain(&self, backends: &[&str], isbn: &str) -> Result<models::Book, E> {
+        let mut last_err = Error::Validation("invalid backend".to_string());
+        for backend in backends {
+            let mut book = match self.backend_get(backend, isbn).await {
+                Ok(book) => book,
+                Err(Error::NotFound) => {
+                    last_err = Error::NotFound;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            book.source = Some(backend.to_string());
+
+            if self.verify_thumbnail {
+                book.image_url = self
+                    .thumbnail_resolve(backend, isbn, book.image_url.take())
+                    .await;
+            }
+
+            return Ok(book);
+        }
+        Err(last_err)
+    }
+
+    // first image which actually exists, none when no backend has one
+    async fn thumbnail_resolve(
+        &self,
+        backend: &str,
+        isbn: &str,
+        image_url: Option<String>,
+    ) -> Option<String> {
+        if let Some(image_url) = image_url {
+            if upstream::image_exists(&self.agent, &image_url).await {
+                return Some(image_url);
+            }
+        }
+
+        for fallback in THUMBNAIL_BACKENDS
+            .iter()
+            .filter(|fallback| **fallback != backend)
+        {
+            let Ok(book) = self.backend_get(fallback, isbn).await else {
+                continue;
+            };
+            let Some(image_url) = book.image_url else {
+                continue;
+            };
+            if upstream::image_exists(&self.agent, &image_url).await {
+                return Some(image_url);
+            }
+        }
+
+        None
+    }
+
+    async fn backend_get(&self, backend: &str, isbn: &str) -> Result<models::Book, E> {
+        self.configured_check(backend)?;
+
+        match backend {
+            "ndl" => self.ndl.book_get(isbn).await,
+            "google" => self.google.book_get(isbn).await,
+            "rakuten" => self.rakuten.book_get(isbn).await,
+            "openbd" => self.openbd.book_get(isbn).await,
+            _ => Err(Error::Validation("invalid backend".to_string())),
+        }
+    }
+
+    // get books by multiple isbn concurrently
+    // invalid or not found isbn is mapped to none
+    pub async fn book_get_many(
+        &self,
+        backend: &str,
+        isbns: &[String],
+    ) -> HashMap<String, Option<models::Book>> {
+        stream::iter(isbns)
+            .map(|text| async move {
+                let item = match isbn::normalize(text) {
+                    Some(isbn) => self.book_get(backend, &isbn).await.ok(),
+                    None => None,
+                };
+                (text.clone(), item)
+            })
+            .buffer_unordered(BULK_CONCURRENCY)
+            .collect()
+            .await
+    }
+}
+
+// editions of one work share title and primary creator but not isbn,
+// newest edition represents the group at the place of its first edition
+// collapsed within the page, so total count is of editions
+pub fn collapse_editions(items: Vec<models::Book>) -> Vec<models::Book> {
+    let mut groups: Vec<(String, Vec<models::Book>)> = vec![];
+    for item in items {
+        let key = edition_key(&item);
+        match groups.iter_mut().find(|(other, _)| *other == key) {
+            Some((_, group)) => group.push(item),
+            None => groups.push((key, vec![item])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter_map(|(_, mut group)| {
+            // none is older than any date, and the first wins on tie
+            let newest = group
+                .iter()
+                .enumerate()
+                .rev()
+                .max_by(|(_, a), (_, b)| a.issued_at.cmp(&b.issued_at))?
+                .0;
+            let mut book = group.remove(newest);
+
+            for other in group {
+                book.add_other_isbns(other.other_isbns.into_iter().chain(other.isbn));
+            }
+
+            Some(book)
+        })
+        .collect()
+}
+
+fn edition_key(item: &models::Book) -> String {
+    let creator = item
+        .creators
+        .first()
+        .map(String::as_str)
+        .unwrap_or_default();
+
+    format!("{}\0{}", fold(&item.title), fold(creator))
+}
+
+// sum of field weights over query terms, each term counts once per field
+fn relevance(item: &models::Book, terms: &[String]) -> u32 {
+    let title = fold(&item.title);
+    let creators: Vec<_> = item.creators.iter().map(|text| fold(text)).collect();
+    let descriptions: Vec<_> = item
+        .descriptions
+        .iter()
+        .chain(&item.keywords)
+        .map(|text| fold(text))
+        .collect();
+
+    terms
+        .iter()
+        .map(|term| {
+            let mut score = 0;
+            if title.contains(term.as_str()) {
+                score += TITLE_WEIGHT;
+            }
+            if creators.iter().any(|text| text.contains(term.as_str())) {
+                score += CREATOR_WEIGHT;
+            }
+            if descriptions.iter().any(|text| text.contains(term.as_str())) {
+                score += DESCRIPTION_WEIGHT;
+            }
+            score
+        })
+        .sum()
+}
+
+// interleave backend results by rank, so that merged list of smaller window is
+// always a prefix of larger one and page boundaries line up across requests
+// books of the returned page are ordered by relevance to the query, backend rank breaks ties;
+// ranking the whole window would order each page over a different set of books
+fn merge_window(
+    chunks: Vec<models::BookChunk>,
+    any: &str,
+    page_size: u32,
+    page: u32,
+) -> models::BookChunk {
+    let fetched: usize = chunks.iter().map(|chunk| chunk.items.len()).sum();
+    let total: u32 = chunks.iter().map(|chunk| chunk.total_count).sum();
+    let depth = chunks
+        .iter()
+        .map(|chunk| chunk.items.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut seen = HashMap::new();
+    let mut merged: Vec<models::Book> = vec![];
+    for rank in 0..depth {
+        for chunk in &chunks {
+            let Some(item) = chunk.items.get(rank) else {
+                continue;
+            };
+
+            // later record of the same book only fills fields missing in the first
+            let key = item.dedup_key();
+            match seen.get(&key) {
+                Some(&index) => {
+                    let first = std::mem::take(&mut merged[index]);
+                    merged[index] = models::Book::merge(first, item.clone());
+                }
+                None => {
+                    seen.insert(key, merged.len());
+                    merged.push(item.clone());
+                }
+            }
+        }
+    }
+
+    let total_count = match fetched {
+        0 => 0,
+        _ => ((total as f64 * merged.len() as f64 / fetched as f64).round() as u32)
+            .max(merged.len() as u32),
+    };
+
+    let mut items: Vec<_> = merged
+        .into_iter()
+        .skip(page_size.saturating_mul(page) as usize)
+        .take(page_size as usize)
+        .collect();
+
+    let terms: Vec<_> = any
+        .split_whitespace()
+        .map(fold)
+        .filter(|term| !term.is_empty())
+        .collect();
+    items.sort_by_cached_key(|item| std::cmp::Reverse(relevance(item, &terms)));
+
+    models::BookChunk {
+        items,
+        total_count,
+        page_info: models::PageInfo::new(page, page_size, total_count),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{collapse_editions, merge_window, BookAppState};
+    use crate::{
+        error::Error,
+        google_api::GoogleAppState,
+        models::{Book, BookChunk, BookFields},
+        ndl_api::NdlAppState,
+        openbd_api::OpenBdAppState,
+        rakuten_api::RakutenAppState,
+    };
+    use actix_web::{web, App, HttpRequest, HttpResponse};
+    use std::collections::HashSet;
+
+    // ndl has no thumbnail for this isbn
+    const NDL_FIXTURE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<searchRetrieveResponse xmlns="http://www.loc.gov/zing/srw/">
+<numberOfRecords>1</numberOfRecords>
+<records>
+<record>
+<recordData>
+<dcndl_simple:dc xmlns:dcndl_simple="http://ndl.go.jp/dcndl/dcndl_simple/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+<dc:title>サムネイルのない本</dc:title>
+<dc:identifier xsi:type="dcndl:ISBN">9784999999996</dc:identifier>
+</dcndl_simple:dc>
+</recordData>
+</record>
+</records>
+</searchRetrieveResponse>"#;
+
+    async fn sru() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("application/xml")
+            .body(NDL_FIXTURE)
+    }
+
+    async fn volumes(req: HttpRequest) -> HttpResponse {
+        let host = req.connection_info().host().to_string();
+        HttpResponse::Ok().json(serde_json::json!({
+            "totalItems": 1,
+            "items": [{
+                "volumeInfo": {
+                    "title": "サムネイルのない本",
+                    "imageLinks": { "smallThumbnail": format!("http://{host}/cover.jpg") }
+                }
+            }]
+        }))
+    }
+
+    async fn cover() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("image/jpeg")
+            .body(vec![0; 2048])
+    }
+
+    #[actix_web::test]
+    async fn test_book_query() {
+        let app = BookAppState::default();
+        let fields = BookFields::default();
+
+        let res = app
+            .book_query("ndl", "4-7981-2196-7", &fields, None, 20, 0)
+            .await
+            .unwrap();
+        println!("book query by isbn: \"{res:?}\"");
+        assert_eq!(res.total_count, 1);
+        assert_eq!(res.items.len(), 1);
+        assert!(res.items[0].title.contains("ドメイン駆動設計"));
+
+        let res = app
+            .book_query("ndl", "ドメイン駆動設計", &fields, None, 20, 0)
+            .await
+            .unwrap();
+        println!("book query by text: \"{res:?}\"");
+        assert!(res.total_count > 1);
+    }
+
+    #[actix_web::test]
+    async fn test_book_get_many() {
+        let app = BookAppState::default();
+
+        let isbns = vec!["978-4-7981-2196-3".to_string(), "9784999999996".to_string()];
+        let res = app.book_get_many("ndl", &isbns).await;
+        println!("book get many: \"{res:?}\"");
+
+        assert_eq!(res.len(), 2);
+        assert!(res["978-4-7981-2196-3"].is_some());
+        assert!(res["9784999999996"].is_none());
+    }
+
+    #[test]
+    fn test_collapse_editions() {
+        let book = |title: &str, creator: &str, isbn: &str, issued_at: &str| Book {
+            title: title.to_string(),
+            creators: vec![creator.to_string()],
+            isbn: Some(isbn.to_string()),
+            issued_at: Some(issued_at.to_string()),
+            ..Default::default()
+        };
+        let items = vec![
+            book("ドメイン駆動設計", "Evans, Eric", "9784798121963", "2011"),
+            book(
+                "リーダブルコード",
+                "Boswell, Dustin",
+                "9784873115658",
+                "2012",
+            ),
+            // later edition, title differs only in width and spacing
+            book(
+                "ドメイン駆動設計 ",
+                "Ｅｖａｎｓ, Eric",
+                "9784798126708",
+                "2013-04",
+            ),
+        ];
+
+        let items = collapse_editions(items);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].isbn.as_deref(), Some("9784798126708"));
+        assert_eq!(items[0].other_isbns, vec!["9784798121963"]);
+        assert_eq!(items[1].title, "リーダブルコード");
+        assert!(items[1].other_isbns.is_empty());
+
+        // other isbns are not serialized when nothing collapsed
+        let value = serde_json::to_value(&items[1]).unwrap();
+        assert!(value.get("otherIsbns").is_none());
+        let value = serde_json::to_value(&items[0]).unwrap();
+        assert_eq!(value["otherIsbns"][0], "9784798121963");
+    }
+
+    #[test]
+    fn test_merge_window() {
+        // three backends sharing some books in different rank
+        let backends: Vec<Vec<&str>> = vec![
+            vec![
+                "9784798121963",
+                "9784001141276",
+                "9784101010014",
+                "9784003101018",
+            ],
+            vec!["9784001141276", "9784798121963", "9784041026229"],
+            vec![
+                "9784101010014",
+                "9784061593106",
+                "9784003101018",
+                "9784041026229",
+            ],
+        ];
+        let window = |size: usize| -> Vec<BookChunk> {
+            backends
+                .iter()
+                .map(|isbns| BookChunk {
+                    items: isbns
+                        .iter()
+                        .take(size)
+                        .map(|isbn| Book {
+                            isbn: Some(isbn.to_string()),
+                            // books deep in every backend match the query best
+                            title: match isbn.ends_with("229") || isbn.ends_with("106") {
+                                true => "ぐりとぐら".to_string(),
+                                false => "本".to_string(),
+                            },
+                            ..Default::default()
+                        })
+                        .collect(),
+                    total_count: isbns.len() as u32,
+                    ..Default::default()
+                })
+                .collect()
+        };
+
+        // relevance must not move books across pages
+        let page_size = 2;
+        for any in ["", "ぐりとぐら"] {
+            let mut seen = HashSet::new();
+            for page in 0..4 {
+                let chunk = merge_window(
+                    window((page_size * (page + 1)) as usize),
+                    any,
+                    page_size,
+                    page,
+                );
+                for item in chunk.items {
+                    assert!(
+                        seen.insert(item.isbn.unwrap()),
+                        "repeated on page {page} of {any:?}"
+                    );
+                }
+            }
+            assert_eq!(seen.len(), 6);
+        }
+
+        // 6 distinct out of 11
+        let chunk = merge_window(window(4), "", 20, 0);
+        assert_eq!(chunk.items.len(), 6);
+        assert_eq!(chunk.total_count, 6);
+        assert!(!chunk.page_info.has_next);
+    }
+
+    #[test]
+    fn test_merge_window_relevance() {
+        let book = |isbn: &str, title: &str, description: &str| Book {
+            isbn: Some(isbn.to_string()),
+            title: title.to_string(),
+            descriptions: vec![description.to_string()],
+            ..Default::default()
+        };
+        let chunk = |items: Vec<Book>| BookChunk {
+            total_count: items.len() as u32,
+            items,
+            ..Default::default()
+        };
+        let chunks = vec![
+            chunk(vec![book(
+                "9784001141276",
+                "星の王子さまの読み方",
+                "ぐりとぐらの作者による評論",
+            )]),
+            chunk(vec![book(
+                "9784834000825",
+                "ぐりとぐら",
+                "のねずみのふたごのお話",
+            )]),
+        ];
+
+        let res = merge_window(chunks.clone(), "ぐりとぐら", 10, 0);
+        assert_eq!(res.items[0].title, "ぐりとぐら");
+        assert_eq!(res.items[1].title, "星の王子さまの読み方");
+
+        // no term matches, backend rank is kept
+        let res = merge_window(chunks, "", 10, 0);
+        assert_eq!(res.items[0].title, "星の王子さまの読み方");
+    }
+
+    #[actix_web::test]
+    async fn test_thumbnail_fallback() {
+        let srv = actix_test::start(|| {
+            App::new()
+                .route("/api/sru", web::get().to(sru))
+                .route("/books/v1/volumes", web::get().to(volumes))
+                .route("/cover.jpg", web::head().to(cover))
+        });
+        let base_url = format!("http://{}", srv.addr());
+        let app = BookAppState::new(
+            NdlAppState::new().with_base_url(&base_url),
+            GoogleAppState::new("appkey").with_base_url(&base_url),
+            RakutenAppState::new("appkey").with_base_url(&base_url),
+            OpenBdAppState::new().with_base_url(&base_url),
+        );
+
+        // synthesized ndl url is returned as is without verification
+        let res = app.book_get("ndl", "9784999999996").await.unwrap();
+        assert!(res.image_url.unwrap().contains("ndl.go.jp"));
+
+        let app = app.with_verify_thumbnail(true);
+        let res = app.book_get("ndl", "9784999999996").await.unwrap();
+        assert_eq!(res.image_url, Some(format!("{base_url}/cover.jpg")));
+    }
+
+    #[actix_web::test]
+    async fn test_book_get_fallback() {
+        let srv = actix_test::start(|| {
+            App::new()
+                .route(
+                    "/api/sru",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().content_type("application/xml").body(
+                            r#"<?xml version="1.0" encoding="UTF-8"?>
+<searchRetrieveResponse xmlns="http://www.loc.gov/zing/srw/">
+<numberOfRecords>0</numberOfRecords>
+</searchRetrieveResponse>"#,
+                        )
+                    }),
+                )
+                .route("/books/v1/volumes", web::get().to(volumes))
+        });
+        let base_url = format!("http://{}", srv.addr());
+        let app = BookAppState::new(
+            NdlAppState::new().with_base_url(&base_url),
+            GoogleAppState::new("appkey").with_base_url(&base_url),
+            RakutenAppState::new("appkey").with_base_url(&base_url),
+            OpenBdAppState::new().with_base_url(&base_url),
+        )
+        .with_default_backend("ndl")
+        .with_fallback_backends(&["ndl", "google"]);
+
+        let res = app.book_get_default("9784999999996").await.unwrap();
+        assert_eq!(res.title, "サムネイルのない本");
+        assert_eq!(res.source.as_deref(), Some("google"));
+
+        // explicit backend does not fall back
+        let err = app.book_get("ndl", "9784999999996").await.unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+
+        let res = app.book_get("google", "9784999999996").await.unwrap();
+        assert_eq!(res.source.as_deref(), Some("google"));
+
+        // backend without api key is skipped in fallback and refused explicitly
+        let app = app.with_backends(&["ndl", "openbd"]);
+        let err = app.book_get_default("9784999999996").await.unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+        let err = app.book_get("google", "9784999999996").await.unwrap_err();
+        assert_eq!(err.to_string(), "invalid: backend not configured");
+
+        // failing default backend is reported rather than hidden by a fallback
+        let srv = actix_test::start(|| {
+            App::new()
+                .route(
+                    "/api/sru",
+                    web::get().to(|| async { HttpResponse::InternalServerError().finish() }),
+                )
+                .route("/books/v1/volumes", web::get().to(volumes))
+        });
+        let base_url = format!("http://{}", srv.addr());
+        let app = BookAppState::new(
+            NdlAppState::new().with_base_url(&base_url),
+            GoogleAppState::new("appkey").with_base_url(&base_url),
+            RakutenAppState::new("appkey").with_base_url(&base_url),
+            OpenBdAppState::new().with_base_url(&base_url),
+        )
+        .with_default_backend("ndl")
+        .with_fallback_backends(&["ndl", "google"]);
+        let err = app.book_get_default("9784999999996").await.unwrap_err();
+        assert!(err.is_upstream());
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// in-memory cache which forgets entry after ttl, shared by clones
+// zero ttl disables caching
+#[derive(Debug, Clone)]
+pub struct TtlCache<K, V> {
+    entries: Arc<Mutex<HashMap<K, (Instant, V)>>>,
+    ttl: Duration,
+}
+
+impl<K, V> Default for TtlCache<K, V> {
+    fn default() -> Self {
+        Self::new(Duration::ZERO)
+    }
+}
+
+impl<K, V> TtlCache<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Default::default(),
+            ttl,
+        }
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().ok()?;
+        let (inserted_at, value) = entries.get(key)?;
+
+        match inserted_at.elapsed() < self.ttl {
+            true => Some(value.clone()),
+            false => None,
+        }
+    }
+
+    // expired entries are swept on insert
+    pub fn insert(&self, key: K, value: V) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+
+        entries.retain(|_, (inserted_at, _)| inserted_at.elapsed() < self.ttl);
+        entries.insert(key, (Instant::now(), value));
+    }
+}
+
+// in-memory cache which forgets least recently used entry over capacity, shared by clones
+// zero capacity disables caching
+#[derive(Debug, Clone)]
+pub struct LruCache<K, V> {
+    entries: Arc<Mutex<LruEntries<K, V>>>,
+    capacity: usize,
+}
+
+#[derive(Debug)]
+struct LruEntries<K, V> {
+    // tick of last use per entry
+    items: HashMap<K, (u64, V)>,
+    tick: u64,
+}
+
+impl<K, V> Default for LruCache<K, V> {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<K, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(LruEntries {
+                items: HashMap::new(),
+                tick: 0,
+            })),
+            capacity,
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> LruCache<K, V> {
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock().ok()?;
+        entries.tick += 1;
+        let tick = entries.tick;
+        let (used_at, value) = entries.items.get_mut(key)?;
+        *used_at = tick;
+        Some(value.clone())
+    }
+
+    // capacity is small, so finding the oldest entry by scan is enough
+    pub fn insert(&self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+
+        entries.tick += 1;
+        let tick = entries.tick;
+        entries.items.insert(key, (tick, value));
+
+        while entries.items.len() > self.capacity {
+            let Some(oldest) = entries
+                .items
+                .iter()
+                .min_by_key(|(_, (used_at, _))| *used_at)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            entries.items.remove(&oldest);
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.items.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LruCache, TtlCache};
+    use std::time::Duration;
+
+    #[test]
+    fn test_ttl_cache() {
+        let cache = TtlCache::new(Duration::from_millis(50));
+        cache.insert("key", 1);
+        assert_eq!(cache.get(&"key"), Some(1));
+        assert_eq!(cache.clone().get(&"key"), Some(1));
+        assert_eq!(cache.get(&"other"), None);
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get(&"key"), None);
+
+        let cache = TtlCache::new(Duration::ZERO);
+        cache.insert("key", 1);
+        assert_eq!(cache.get(&"key"), None);
+    }
+
+    #[test]
+    fn test_lru_cache() {
+        let cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(1));
+
+        // b is least recently used
+        cache.insert("c", 3);
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.clone().get(&"a"), Some(1));
+        assert_eq!(cache.get(&"c"), Some(3));
+
+        cache.clear();
+        assert_eq!(cache.get(&"a"), None);
+
+        let cache = LruCache::new(0);
+        cache.insert("a", 1);
+        assert_eq!(cache.get(&"a"), None);
+    }
+}
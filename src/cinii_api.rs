@@ -1,34 +1,89 @@
-use crate::models;
+use crate::{
+    error::Error,
+    library_name, models,
+    upstream::{self, Agent, Breaker, BreakerStatus, Limiter, Retry},
+};
 use actix_web::web::Buf;
-use anyhow::Context;
-use awc::Client;
 use roxmltree::Node;
-use std::{error::Error, io::Read};
+use std::io::Read;
 
-type E = Box<dyn Error>;
+type E = Error;
 
-#[derive(Debug, Default, Clone)]
+const BASE_URL: &str = "https://ci.nii.ac.jp";
+
+#[derive(Debug, Clone)]
 pub struct CiniiAppState {
     appkey: String,
+    base_url: String,
+    limiter: Limiter,
+    retry: Retry,
+    breaker: Breaker,
+    agent: Agent,
+}
+
+impl Default for CiniiAppState {
+    fn default() -> Self {
+        Self {
+            appkey: String::new(),
+            base_url: BASE_URL.to_string(),
+            limiter: Default::default(),
+            retry: Default::default(),
+            breaker: Default::default(),
+            agent: Default::default(),
+        }
+    }
 }
 
 impl CiniiAppState {
     pub fn new(appkey: &str) -> Self {
         Self {
             appkey: appkey.to_string(),
+            ..Self::default()
         }
     }
 
-    pub async fn holder_query(
-        &self,
-        isbn: &str,
-        page_size: u32,
-        page: u32,
-    ) -> Result<models::HolderChunk, E> {
-        let mut reader = Client::default()
-            .get("https://ci.nii.ac.jp/books/opensearch/search")
-            .query(&[("appid", self.appkey.as_str()), ("isbn", isbn)])?
-            .send()
+    pub fn with_limiter(self, limiter: Limiter) -> Self {
+        Self { limiter, ..self }
+    }
+
+    pub fn with_retry(self, retry: Retry) -> Self {
+        Self { retry, ..self }
+    }
+
+    pub fn with_breaker(self, breaker: Breaker) -> Self {
+        Self { breaker, ..self }
+    }
+
+    pub fn with_agent(self, agent: Agent) -> Self {
+        Self { agent, ..self }
+    }
+
+    pub fn breaker_status(&self) -> BreakerStatus {
+        self.breaker.status()
+    }
+
+    // point to another host, e.g. local mock server
+    pub fn with_base_url(self, base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            ..self
+        }
+    }
+
+    // ncid of the book which cinii books catalogs, none when it has no record
+    pub async fn ncid_for_isbn(&self, isbn: &str) -> Result<Option<String>, E> {
+        let isbn = upstream::query_text(isbn)?;
+
+        let _permit = self.limiter.acquire().await?;
+
+        let request = self
+            .agent
+            .client()
+            .get(format!("{}/books/opensearch/search", self.base_url))
+            .query(&[("appid", self.appkey.as_str()), ("isbn", isbn.as_str())])?;
+        let mut reader = self
+            .breaker
+            .send(&self.retry, request)
             .await?
             .body()
             .await?
@@ -38,12 +93,35 @@ impl CiniiAppState {
         reader.read_to_string(&mut text)?;
         let document = roxmltree::Document::parse(&text)?;
         let root = document.root_element();
-        let ncid = parse_ncid(root).context("failed to parse")?;
+        let ncid = parse_ncid(root).ok_or(Error::Parse("no opensearch response".to_string()))?;
 
-        let mut reader = Client::default()
-            .get("https://ci.nii.ac.jp/books/opensearch/holder")
-            .query(&[("appid", self.appkey.as_str()), ("ncid", ncid.as_str())])?
-            .send()
+        Ok(ncid)
+    }
+
+    pub async fn holder_query(
+        &self,
+        isbn: &str,
+        page_size: u32,
+        page: u32,
+    ) -> Result<models::HolderChunk, E> {
+        let Some(ncid) = self.ncid_for_isbn(isbn).await? else {
+            // no university library holds unknown book
+            return Ok(models::HolderChunk {
+                page_info: models::PageInfo::new(page, page_size, 0),
+                ..Default::default()
+            });
+        };
+
+        let _permit = self.limiter.acquire().await?;
+
+        let request = self
+            .agent
+            .client()
+            .get(format!("{}/books/opensearch/holder", self.base_url))
+            .query(&[("appid", self.appkey.as_str()), ("ncid", ncid.as_str())])?;
+        let mut reader = self
+            .breaker
+            .send(&self.retry, request)
             .await?
             .body()
             .await?
@@ -53,7 +131,7 @@ impl CiniiAppState {
         reader.read_to_string(&mut text)?;
         let document = roxmltree::Document::parse(&text)?;
         let root = document.root_element();
-        let chunk = parse_holder(root).context("failed to parse")?;
+        let chunk = parse_holder(root).ok_or(Error::Parse("no holder page".to_string()))?;
 
         let items: Vec<_> = chunk
             .items
@@ -62,6 +140,9 @@ impl CiniiAppState {
                 isbn: isbn.to_string(),
                 library_name: item.library_name,
                 state: item.state,
+                label: None,
+                source: Some(models::HolderSource::Cinii),
+                ..Default::default()
             })
             .skip((page_size * page) as usize)
             .take(page_size as usize)
@@ -70,20 +151,23 @@ impl CiniiAppState {
         Ok(models::HolderChunk {
             items,
             total_count: chunk.total_count,
+            page_info: models::PageInfo::new(page, page_size, chunk.total_count),
         })
     }
 }
 
-fn parse_ncid(node: Node) -> Option<String> {
+// none when response is not a search result, inner none when nothing matched
+fn parse_ncid(node: Node) -> Option<Option<String>> {
+    node.children()
+        .find(|node| node.has_tag_name("totalResults"))?;
+
     let ncid = node
         .children()
-        .find(|node| node.has_tag_name("entry"))?
-        .children()
-        .find(|node| node.has_tag_name("id"))?
-        .text()?
-        .split('/')
-        .last()?
-        .to_string();
+        .find(|node| node.has_tag_name("entry"))
+        .and_then(|node| node.children().find(|node| node.has_tag_name("id")))
+        .and_then(|node| node.text())
+        .and_then(|text| text.rsplit('/').next())
+        .map(|text| text.to_string());
     Some(ncid)
 }
 
@@ -108,8 +192,8 @@ fn parse_holder(node: Node) -> Option<HolderChunk> {
             let library_name = node
                 .children()
                 .find(|node| node.has_tag_name("title"))?
-                .text()?
-                .replace(' ', "");
+                .text()
+                .map(library_name::normalize)?;
 
             Some(Holder {
                 library_name,
@@ -131,8 +215,112 @@ fn parse_holder(node: Node) -> Option<HolderChunk> {
 #[cfg(test)]
 mod test {
     use super::CiniiAppState;
+    use crate::error::Error;
+    use actix_web::{web, App, HttpResponse};
     use std::env;
 
+    const SEARCH: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:opensearch="http://a9.com/-/spec/opensearch/1.1/">
+<title>CiNii Books OpenSearch</title>
+<opensearch:totalResults>1</opensearch:totalResults>
+<entry>
+<title>ないた赤おに</title>
+<id>https://ci.nii.ac.jp/ncid/BA12345678</id>
+</entry>
+</feed>"#;
+
+    const HOLDER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:opensearch="http://a9.com/-/spec/opensearch/1.1/">
+<title>CiNii Books OpenSearch</title>
+<opensearch:totalResults>2</opensearch:totalResults>
+<entry>
+<title>東京大学 総合図書館</title>
+<id>https://ci.nii.ac.jp/library/FA000001</id>
+</entry>
+<entry>
+<title>京都大学 附属図書館</title>
+<id>https://ci.nii.ac.jp/library/FA000002</id>
+</entry>
+</feed>"#;
+
+    async fn search() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("application/xml")
+            .body(SEARCH)
+    }
+
+    async fn holder(query: web::Query<Vec<(String, String)>>) -> HttpResponse {
+        match query.contains(&("ncid".to_string(), "BA12345678".to_string())) {
+            true => HttpResponse::Ok()
+                .content_type("application/xml")
+                .body(HOLDER),
+            false => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_cinii_mock() {
+        let srv = actix_test::start(|| {
+            App::new()
+                .route("/books/opensearch/search", web::get().to(search))
+                .route("/books/opensearch/holder", web::get().to(holder))
+        });
+        let app = CiniiAppState::new("appkey").with_base_url(&format!("http://{}", srv.addr()));
+
+        let res = app.holder_query("9784001141276", 20, 0).await.unwrap();
+        assert_eq!(res.total_count, 2);
+        assert_eq!(res.items[0].library_name, "東京大学総合図書館");
+        assert_eq!(res.items[1].isbn, "9784001141276");
+
+        let res = app.ncid_for_isbn("9784001141276").await.unwrap();
+        assert_eq!(res.as_deref(), Some("BA12345678"));
+    }
+
+    const EMPTY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:opensearch="http://a9.com/-/spec/opensearch/1.1/">
+<title>CiNii Books OpenSearch</title>
+<opensearch:totalResults>0</opensearch:totalResults>
+</feed>"#;
+
+    const MALFORMED: &str = "<feed><entry>";
+
+    #[actix_web::test]
+    async fn test_cinii_empty() {
+        let srv = actix_test::start(|| {
+            App::new().route(
+                "/books/opensearch/search",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .content_type("application/xml")
+                        .body(EMPTY)
+                }),
+            )
+        });
+        let app = CiniiAppState::new("appkey").with_base_url(&format!("http://{}", srv.addr()));
+
+        let res = app.holder_query("9784001141276", 20, 0).await.unwrap();
+        assert!(res.items.is_empty());
+        assert_eq!(res.total_count, 0);
+
+        let res = app.ncid_for_isbn("9784001141276").await.unwrap();
+        assert!(res.is_none());
+
+        let srv = actix_test::start(|| {
+            App::new().route(
+                "/books/opensearch/search",
+                web::get().to(|| async {
+                    HttpResponse::Ok()
+                        .content_type("application/xml")
+                        .body(MALFORMED)
+                }),
+            )
+        });
+        let app = CiniiAppState::new("appkey").with_base_url(&format!("http://{}", srv.addr()));
+
+        let err = app.holder_query("9784001141276", 20, 0).await.unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+    }
+
     #[actix_web::test]
     async fn test_cinii() {
         let appkey = env::var("CINII_APPKEY").unwrap();
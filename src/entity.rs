@@ -1,22 +1,159 @@
-use crate::models::{Reserve, ReserveChunk, Session, User};
-use anyhow::Context;
+use crate::auth::{self, AuthMode};
+use crate::error::Error;
+use crate::isbn;
+use crate::issued;
+use crate::models::{
+    Book, Bookmark, BookmarkChunk, Creator, Favorites, HolderSnapshot, HolderState, Library,
+    PageInfo, Reserve, ReserveChunk, ReserveCursor, ReserveFilter, Session, SessionInfo, User,
+};
 use base64::Engine;
-use chrono::Utc;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use rand::Rng;
-use sqlx::PgPool;
-use std::error::Error;
+use sqlx::{postgres::PgPoolOptions, PgPool, Postgres, Transaction};
+use std::{collections::HashMap, time::Duration};
+use tokio::sync::broadcast;
 
-type E = Box<dyn Error>;
+type E = Error;
+
+pub const FAVORITES: &str = "@favorites";
+
+// reserve changes buffered for slow subscribers, older ones are dropped
+const RESERVE_EVENT_CAPACITY: usize = 64;
+
+const PASSWORD_RESET_TTL_MINUTES: i64 = 30;
+
+// active reserves per user unless configured
+const MAX_ACTIVE_RESERVES: u32 = 20;
+
+// characters of session token shown to operators
+const SESSION_PREFIX_LEN: usize = 8;
+
+// defaults are sized for a single azure functions instance, scaled out instances share
+// the connection limit of the database server, and idle connection is closed before
+// azure load balancer silently drops it after 4 minutes
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    pub acquire_timeout: Duration,
+    pub idle_timeout: Duration,
+    // initial connect is retried with exponential backoff, db may start later than app
+    pub connect_attempts: u32,
+    pub connect_backoff: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 5,
+            acquire_timeout: Duration::from_secs(10),
+            idle_timeout: Duration::from_secs(60 * 3),
+            connect_attempts: 5,
+            connect_backoff: Duration::from_millis(500),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Entity {
     pool: PgPool,
+    auth_mode: AuthMode,
+    require_verified: bool,
+    max_active_reserves: u32,
+    reserve_events: broadcast::Sender<Reserve>,
 }
 
 impl Entity {
+    // tests connect with default pool, server configures it from env
+    #[cfg(test)]
     pub async fn new(db_url: &str) -> Result<Self, E> {
-        let pool = PgPool::connect(db_url).await?;
-        Ok(Entity { pool })
+        Self::with_pool_config(db_url, &PoolConfig::default()).await
+    }
+
+    // every later query fails, for database failure of handlers
+    #[cfg(test)]
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    pub async fn with_pool_config(db_url: &str, config: &PoolConfig) -> Result<Self, E> {
+        let pool = connect_retry(config.connect_attempts, config.connect_backoff, || {
+            PgPoolOptions::new()
+                .max_connections(config.max_connections)
+                .acquire_timeout(config.acquire_timeout)
+                .idle_timeout(config.idle_timeout)
+                .connect(db_url)
+        })
+        .await?;
+        Ok(Entity {
+            pool,
+            auth_mode: AuthMode::default(),
+            require_verified: false,
+            max_active_reserves: MAX_ACTIVE_RESERVES,
+            reserve_events: broadcast::channel(RESERVE_EVENT_CAPACITY).0,
+        })
+    }
+
+    // apply pending migrations in order, already applied ones are skipped
+    // return versions applied by this call
+    pub async fn migrate(&self) -> Result<Vec<i64>, E> {
+        let migrator = sqlx::migrate!();
+
+        // bookkeeping table does not exist before first run
+        let applied: Vec<i64> = sqlx::query_scalar("SELECT version FROM _sqlx_migrations")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap_or_default();
+
+        migrator.run(&self.pool).await?;
+
+        let mut versions = vec![];
+        for migration in migrator.iter() {
+            if migration.migration_type.is_down_migration() || applied.contains(&migration.version)
+            {
+                continue;
+            }
+            eprintln!(
+                "applied migration {} {}",
+                migration.version, migration.description
+            );
+            versions.push(migration.version);
+        }
+
+        Ok(versions)
+    }
+
+    pub fn with_auth_mode(self, auth_mode: AuthMode) -> Self {
+        Self { auth_mode, ..self }
+    }
+
+    // reject reserve creation by user whose email is not verified yet
+    pub fn with_require_verified(self, require_verified: bool) -> Self {
+        Self {
+            require_verified,
+            ..self
+        }
+    }
+
+    pub fn require_verified(&self) -> bool {
+        self.require_verified
+    }
+
+    // reserves which are not completed yet per user, like loan limit of library
+    pub fn with_max_active_reserves(self, max_active_reserves: u32) -> Self {
+        Self {
+            max_active_reserves,
+            ..self
+        }
+    }
+
+    // every created or advanced reserve of all users, receiver filters by user
+    pub fn reserve_subscribe(&self) -> broadcast::Receiver<Reserve> {
+        self.reserve_events.subscribe()
+    }
+
+    // nobody may be listening, which is not an error
+    fn reserve_notify(&self, reserve: Reserve) {
+        let _ = self.reserve_events.send(reserve);
     }
 
     pub async fn user_create(
@@ -34,7 +171,13 @@ impl Entity {
             address
         )
         .execute(&self.pool)
-        .await?;
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(err) if err.code().as_deref() == Some("23505") => {
+                Error::Conflict("email is already registered".to_string())
+            }
+            err => err.into(),
+        })?;
         Ok(())
     }
 
@@ -45,12 +188,15 @@ impl Entity {
             email,
             password
         )
-        .fetch_one(&self.pool)
-        .await?;
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or(Error::Unauthorized("invalid email or password".to_string()))?;
 
-        let mut buf = [0u8; 32];
-        rand::rngs::OsRng.fill(&mut buf);
-        let token = base64::engine::general_purpose::STANDARD.encode(buf);
+        if let AuthMode::Jwt { secret, ttl } = &self.auth_mode {
+            return auth::jwt_issue(secret, user.id, *ttl);
+        }
+
+        let token = token_generate();
 
         sqlx::query!(
             "INSERT INTO sessions (token, user_id) VALUES ($1, $2)",
@@ -64,18 +210,174 @@ impl Entity {
     }
 
     pub async fn user_logout(&self, token: &str) -> Result<(), E> {
+        // jwt is stateless, expires by itself
+        if let AuthMode::Jwt { .. } = &self.auth_mode {
+            return Ok(());
+        }
+
         sqlx::query!("DELETE FROM sessions WHERE token = $1", token)
             .execute(&self.pool)
             .await?;
         Ok(())
     }
 
-    pub async fn user_get(&self, token: &str) -> Result<User, E> {
+    // sessions of the user, jwt mode has none
+    pub async fn sessions_for_user(&self, user_id: i64) -> Result<Vec<SessionInfo>, E> {
+        let sessions = sqlx::query_as!(
+            Session,
+            "SELECT * FROM sessions WHERE user_id = $1 ORDER BY id",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions
+            .into_iter()
+            .map(|item| SessionInfo {
+                id: item.id,
+                token_prefix: item.token.chars().take(SESSION_PREFIX_LEN).collect(),
+            })
+            .collect())
+    }
+
+    pub async fn revoke_session(&self, id: i64) -> Result<(), E> {
+        let result = sqlx::query!("DELETE FROM sessions WHERE id = $1", id)
+            .execute(&self.pool)
+            .await?;
+
+        match result.rows_affected() {
+            0 => Err(Error::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    // count of revoked sessions
+    pub async fn revoke_all_sessions(&self, user_id: i64) -> Result<u64, E> {
+        let result = sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    // issue single-use reset token, none for unknown email, which caller must not
+    // tell apart so that the email can not be probed
+    pub async fn request_password_reset(&self, email: &str) -> Result<Option<String>, E> {
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE email = $1", email)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+
+        let token = token_generate();
+        sqlx::query!(
+            "INSERT INTO password_resets (token, user_id, expires_at) VALUES ($1, $2, $3)",
+            token,
+            user_id,
+            Utc::now().naive_utc() + chrono::Duration::minutes(PASSWORD_RESET_TTL_MINUTES)
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(Some(token))
+    }
+
+    // consume token and replace password, existing sessions are revoked
+    pub async fn confirm_password_reset(&self, token: &str, new_password: &str) -> Result<(), E> {
+        let mut tx = self.pool.begin().await?;
+
+        let user_id = sqlx::query_scalar!(
+            "UPDATE password_resets SET used_at = $2
+            WHERE token = $1 AND used_at IS NULL AND expires_at > $2
+            RETURNING user_id",
+            token,
+            Utc::now().naive_utc()
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(Error::Unauthorized("invalid or expired token".to_string()))?;
+
+        sqlx::query!(
+            "UPDATE users SET password = $1 WHERE id = $2",
+            new_password,
+            user_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user_id)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // issue single-use token to prove ownership of the email
+    pub async fn issue_verification(&self, email: &str) -> Result<String, E> {
+        let token = token_generate();
+
+        let user_id = sqlx::query_scalar!("SELECT id FROM users WHERE email = $1", email)
+            .fetch_one(&self.pool)
+            .await?;
+
+        sqlx::query!(
+            "INSERT INTO verification_tokens (token, user_id) VALUES ($1, $2)",
+            token,
+            user_id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    // consume token and mark the email as verified
+    pub async fn verify_email(&self, token: &str) -> Result<(), E> {
+        let mut tx = self.pool.begin().await?;
+
+        let user_id = sqlx::query_scalar!(
+            "UPDATE verification_tokens SET used_at = $2
+            WHERE token = $1 AND used_at IS NULL
+            RETURNING user_id",
+            token,
+            Utc::now().naive_utc()
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(Error::Unauthorized("invalid or used token".to_string()))?;
+
+        sqlx::query!(
+            "UPDATE users SET email_verified = TRUE WHERE id = $1",
+            user_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn user_id_get(&self, token: &str) -> Result<i64, E> {
+        if let AuthMode::Jwt { secret, .. } = &self.auth_mode {
+            return auth::jwt_verify(secret, token);
+        }
+
         let session = sqlx::query_as!(Session, "SELECT * FROM sessions WHERE token = $1", token)
             .fetch_one(&self.pool)
             .await?;
 
-        let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", session.user_id)
+        Ok(session.user_id)
+    }
+
+    pub async fn user_get(&self, token: &str) -> Result<User, E> {
+        let user_id = self.user_id_get(token).await?;
+        self.user_get_by_id(user_id).await
+    }
+
+    pub async fn user_get_by_id(&self, user_id: i64) -> Result<User, E> {
+        let user = sqlx::query_as!(User, "SELECT * FROM users WHERE id = $1", user_id)
             .fetch_one(&self.pool)
             .await?;
 
@@ -84,95 +386,1666 @@ impl Entity {
 
     pub async fn reserve_create(
         &self,
-        token: &str,
+        user_id: i64,
         isbn: &str,
         library_name: &str,
+        title: Option<&str>,
     ) -> Result<(), E> {
-        let user = self.user_get(token).await?;
+        let isbn = reserve_isbn(isbn);
 
-        sqlx::query!(
-            "INSERT INTO reserves (user_id, library_name, isbn, state, staging_at) VALUES ($1, $2, $3, $4, $5)",
-            user.id,
+        let mut tx = self.pool.begin().await?;
+        reserve_create_check(
+            &mut tx,
+            user_id,
+            &isbn,
+            library_name,
+            self.max_active_reserves,
+        )
+        .await?;
+
+        queue_lock(&mut tx, &isbn, library_name).await?;
+
+        let reserve = sqlx::query_as!(
+            Reserve,
+            "INSERT INTO reserves (user_id, library_name, isbn, state, staging_at, title, queue_position, updated_at) VALUES ($1, $2, $3, $4, $5, $6,
+            (SELECT COUNT(*) + 1 FROM reserves WHERE library_name = $2::VARCHAR AND isbn = $3::VARCHAR AND state NOT IN ('Completed', 'Cancelled'))::INTEGER, $5) RETURNING *",
+            user_id,
             library_name,
             isbn,
             "Staging",
-            Utc::now().naive_utc()
+            Utc::now().naive_utc(),
+            title
         )
-        .execute(&self.pool)
+        .fetch_one(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.reserve_notify(reserve);
+
+        Ok(())
+    }
+
+    // move reserve to next state, Staging -> Staged -> Reserved (-> Ready) -> Completed
+    // completed or cancelled reserve is not found
+    pub async fn reserve_advance(&self, user_id: i64, id: i64) -> Result<Reserve, E> {
+        let mut tx = self.pool.begin().await?;
+        reserve_lock(&mut tx, user_id, id).await?;
+
+        let reserve = sqlx::query_as!(
+            Reserve,
+            "UPDATE reserves SET
+            state = CASE state WHEN 'Staging' THEN 'Staged' WHEN 'Staged' THEN 'Reserved' ELSE 'Completed' END,
+            staged_at = CASE state WHEN 'Staging' THEN $3 ELSE staged_at END,
+            reserved_at = CASE state WHEN 'Staged' THEN $3 ELSE reserved_at END,
+            completed_at = CASE WHEN state IN ('Reserved', 'Ready') THEN $3 ELSE completed_at END,
+            queue_position = CASE WHEN state IN ('Reserved', 'Ready') THEN NULL ELSE queue_position END,
+            updated_at = $3
+            WHERE id = $1 AND user_id = $2 AND state NOT IN ('Completed', 'Cancelled') RETURNING *",
+            id,
+            user_id,
+            Utc::now().naive_utc(),
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        let moved = match reserve.state.as_str() {
+            "Completed" => reserve_requeue(&mut tx, &reserve.isbn, &reserve.library_name).await?,
+            _ => vec![],
+        };
+
+        tx.commit().await?;
+
+        self.reserve_notify(reserve.clone());
+        moved.into_iter().for_each(|item| self.reserve_notify(item));
+
+        Ok(reserve)
+    }
+
+    // reserved copy is back at the library for the first of the queue,
+    // which stays in the queue until it is completed
+    pub async fn reserve_ready(&self, user_id: i64, id: i64) -> Result<Reserve, E> {
+        let reserve = sqlx::query_as!(
+            Reserve,
+            "UPDATE reserves SET state = 'Ready', ready_at = $3, updated_at = $3
+            WHERE id = $1 AND user_id = $2 AND state = 'Reserved' AND queue_position = 1 RETURNING *",
+            id,
+            user_id,
+            Utc::now().naive_utc(),
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        self.reserve_notify(reserve.clone());
+
+        Ok(reserve)
+    }
+
+    // withdraw reserve, later reserves of the same book move up
+    // cancelled reserve is kept so that delta sync can report it
+    pub async fn reserve_cancel(&self, user_id: i64, id: i64) -> Result<(), E> {
+        let mut tx = self.pool.begin().await?;
+        reserve_lock(&mut tx, user_id, id).await?;
+
+        let reserve = sqlx::query_as!(
+            Reserve,
+            "UPDATE reserves SET state = 'Cancelled', queue_position = NULL, updated_at = $3
+            WHERE id = $1 AND user_id = $2 AND state NOT IN ('Completed', 'Cancelled') RETURNING *",
+            id,
+            user_id,
+            Utc::now().naive_utc(),
+        )
+        .fetch_one(&mut tx)
         .await?;
 
+        let moved = reserve_requeue(&mut tx, &reserve.isbn, &reserve.library_name).await?;
+
+        tx.commit().await?;
+
+        self.reserve_notify(reserve);
+        moved.into_iter().for_each(|item| self.reserve_notify(item));
+
         Ok(())
     }
 
+    // cancelled reserves are listed only when asked by state or by delta sync
     pub async fn reserve_query(
         &self,
-        token: &str,
+        user_id: i64,
         page_size: u32,
         page: u32,
+        filter: &ReserveFilter,
     ) -> Result<ReserveChunk, E> {
-        let user = self.user_get(token).await?;
-
+        let server_time = server_time();
         let items = sqlx::query_as!(
             Reserve,
-            "SELECT * FROM reserves WHERE user_id = $1 ORDER BY staging_at DESC OFFSET $2 LIMIT $3",
-            user.id,
-            (page_size * page) as i64,
+            "SELECT * FROM reserves WHERE user_id = $1
+            AND ($2::VARCHAR IS NULL OR state = $2)
+            AND ($3::TIMESTAMP IS NULL OR staging_at >= $3)
+            AND ($4::TIMESTAMP IS NULL OR staging_at < $4)
+            AND ($5::TIMESTAMP IS NULL OR updated_at >= $5)
+            AND (state <> 'Cancelled' OR $2 IS NOT NULL OR $5 IS NOT NULL)
+            ORDER BY staging_at DESC, id DESC OFFSET $6 LIMIT $7",
+            user_id,
+            filter.state,
+            filter.from,
+            filter.to,
+            filter.modified_since,
+            page as i64 * page_size as i64,
             page_size as i64
         )
         .fetch_all(&self.pool)
         .await?;
 
-        let total_count = sqlx::query!("SELECT COUNT(*) FROM reserves WHERE user_id = $1", user.id)
-            .fetch_one(&self.pool)
-            .await?
-            .count
-            .context("failed to count")? as u32;
+        let total_count = self.reserve_count(user_id, filter).await?;
+        let page_info = PageInfo::new(page, page_size, total_count);
+
+        // offset paging can switch to cursor paging from any page
+        let next_cursor = match page_info.has_next {
+            true => items.last().map(ReserveCursor::from),
+            false => None,
+        };
+
+        Ok(ReserveChunk {
+            items,
+            total_count,
+            page_info,
+            next_cursor,
+            server_time,
+        })
+    }
+
+    // page of reserves older than cursor, in the same order as offset paging
+    pub async fn reserve_query_after(
+        &self,
+        user_id: i64,
+        page_size: u32,
+        cursor: &ReserveCursor,
+        filter: &ReserveFilter,
+    ) -> Result<ReserveChunk, E> {
+        let server_time = server_time();
+        // one more row tells whether next page exists
+        let mut items = sqlx::query_as!(
+            Reserve,
+            "SELECT * FROM reserves WHERE user_id = $1
+            AND ($2::VARCHAR IS NULL OR state = $2)
+            AND ($3::TIMESTAMP IS NULL OR staging_at >= $3)
+            AND ($4::TIMESTAMP IS NULL OR staging_at < $4)
+            AND ($5::TIMESTAMP IS NULL OR updated_at >= $5)
+            AND (state <> 'Cancelled' OR $2 IS NOT NULL OR $5 IS NOT NULL)
+            AND (staging_at, id) < ($6::TIMESTAMP, $7::BIGINT)
+            ORDER BY staging_at DESC, id DESC LIMIT $8",
+            user_id,
+            filter.state,
+            filter.from,
+            filter.to,
+            filter.modified_since,
+            cursor.staging_at,
+            cursor.id,
+            page_size as i64 + 1
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let next_cursor = match items.len() > page_size as usize {
+            true => {
+                items.truncate(page_size as usize);
+                items.last().map(ReserveCursor::from)
+            }
+            false => None,
+        };
+
+        let total_count = self.reserve_count(user_id, filter).await?;
+
+        Ok(ReserveChunk {
+            items,
+            total_count,
+            page_info: PageInfo::new(0, page_size, total_count)
+                .with_has_next(next_cursor.is_some()),
+            next_cursor,
+            server_time,
+        })
+    }
+
+    async fn reserve_count(&self, user_id: i64, filter: &ReserveFilter) -> Result<u32, E> {
+        let total_count = sqlx::query!(
+            "SELECT COUNT(*) FROM reserves WHERE user_id = $1
+            AND ($2::VARCHAR IS NULL OR state = $2)
+            AND ($3::TIMESTAMP IS NULL OR staging_at >= $3)
+            AND ($4::TIMESTAMP IS NULL OR staging_at < $4)
+            AND ($5::TIMESTAMP IS NULL OR updated_at >= $5)
+            AND (state <> 'Cancelled' OR $2 IS NOT NULL OR $5 IS NOT NULL)",
+            user_id,
+            filter.state,
+            filter.from,
+            filter.to,
+            filter.modified_since
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .count
+        .ok_or(Error::Internal("failed to count".to_string()))? as u32;
+
+        Ok(total_count)
+    }
+
+    pub async fn reserve_query_all(&self, user_id: i64) -> Result<Vec<Reserve>, E> {
+        let items = sqlx::query_as!(
+            Reserve,
+            "SELECT * FROM reserves WHERE user_id = $1 AND state <> 'Cancelled' ORDER BY staging_at DESC",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(items)
+    }
+
+    // reserves which are neither completed nor cancelled
+    pub async fn reserve_query_active(&self, user_id: i64) -> Result<Vec<Reserve>, E> {
+        let items = sqlx::query_as!(
+            Reserve,
+            "SELECT * FROM reserves WHERE user_id = $1 AND state NOT IN ('Completed', 'Cancelled') ORDER BY staging_at DESC",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(items)
+    }
+
+    // whether user may create one more reserve, as reserve_create would decide
+    // same checks as reserve_create without creating, for dry run
+    pub async fn reserve_create_allowed(
+        &self,
+        user_id: i64,
+        isbn: &str,
+        library_name: &str,
+    ) -> Result<(), E> {
+        let mut tx = self.pool.begin().await?;
+        reserve_create_check(
+            &mut tx,
+            user_id,
+            &reserve_isbn(isbn),
+            library_name,
+            self.max_active_reserves,
+        )
+        .await
+    }
+
+    // cancelled reserve is not counted either
+    pub async fn reserve_active_count(&self, user_id: i64) -> Result<u32, E> {
+        let count = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM reserves WHERE user_id = $1 AND state NOT IN ('Completed', 'Cancelled')",
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count.unwrap_or_default() as u32)
+    }
+
+    // active reserves of every user in one of the states, oldest first
+    pub async fn reserve_query_states(&self, states: &[String]) -> Result<Vec<Reserve>, E> {
+        let items = sqlx::query_as!(
+            Reserve,
+            "SELECT * FROM reserves WHERE state = ANY($1) AND state NOT IN ('Completed', 'Cancelled') ORDER BY staging_at, id",
+            states
+        )
+        .fetch_all(&self.pool)
+        .await?;
 
-        Ok(ReserveChunk { items, total_count })
+        Ok(items)
     }
 
-    pub async fn reserve_get(&self, token: &str, id: i64) -> Result<Reserve, E> {
-        let user = self.user_get(token).await?;
+    // count reserves by state, user without reserves gets empty map
+    pub async fn reserve_summary(&self, user_id: i64) -> Result<HashMap<String, u32>, E> {
+        let summary = sqlx::query!(
+            "SELECT state, COUNT(*) FROM reserves WHERE user_id = $1 AND state <> 'Cancelled' GROUP BY state",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|row| (row.state, row.count.unwrap_or_default() as u32))
+        .collect();
+
+        Ok(summary)
+    }
 
+    pub async fn reserve_get(&self, user_id: i64, id: i64) -> Result<Reserve, E> {
         let reserve = sqlx::query_as!(
             Reserve,
             "SELECT * FROM reserves WHERE id = $1 AND user_id = $2",
             id,
-            user.id,
+            user_id,
         )
         .fetch_one(&self.pool)
         .await?;
 
         Ok(reserve)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::Entity;
-    use std::env;
+    // record holder state of reserve as checked now
+    // failed check tells nothing about the book, it would hide the previous state
+    pub async fn holder_snapshot_add(&self, reserve_id: i64, state: &HolderState) -> Result<(), E> {
+        if *state == HolderState::Unknown {
+            return Ok(());
+        }
 
-    #[actix_web::test]
-    async fn test_user_create() {
-        let appkey = env::var("DATABASE_URL").unwrap();
-        let app = Entity::new(&appkey).await.unwrap();
-        app.user_create("alice@example2.com", "alice", "アリス", "日本")
-            .await
-            .unwrap();
+        sqlx::query!(
+            "INSERT INTO holder_snapshots (reserve_id, state, checked_at) VALUES ($1, $2, $3)",
+            reserve_id,
+            state.as_str(),
+            Utc::now().naive_utc(),
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
     }
 
-    #[actix_web::test]
-    async fn test_entity() {
-        let appkey = env::var("DATABASE_URL").unwrap();
-        let app = Entity::new(&appkey).await.unwrap();
+    // most recently recorded holder state of reserve, none before first check
+    pub async fn holder_snapshot_latest(&self, reserve_id: i64) -> Result<Option<HolderState>, E> {
+        let state = sqlx::query_scalar!(
+            "SELECT state FROM holder_snapshots WHERE reserve_id = $1 ORDER BY checked_at DESC, id DESC LIMIT 1",
+            reserve_id,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
 
-        let token = app.user_login("alice@example2.com", "alice").await.unwrap();
-        println!("token: {token:?}");
+        state.map(|state| state.parse()).transpose()
+    }
 
-        let user = app.user_get(&token).await.unwrap();
-        println!("user get: {user:?}");
+    // holder states of reserve of the user, oldest first
+    pub async fn reserve_history(&self, user_id: i64, id: i64) -> Result<Vec<HolderSnapshot>, E> {
+        let reserve = self.reserve_get(user_id, id).await?;
 
-        let reserves = app.reserve_query(&token, 20, 0).await.unwrap();
-        println!("reserves query: {reserves:?}");
+        let snapshots = sqlx::query_as!(
+            HolderSnapshot,
+            "SELECT state, checked_at FROM holder_snapshots WHERE reserve_id = $1 ORDER BY checked_at, id",
+            reserve.id,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(snapshots)
+    }
+
+    pub async fn favorite_add(&self, user_id: i64, library_name: &str) -> Result<(), E> {
+        sqlx::query!(
+            "INSERT INTO favorite_libraries (user_id, library_name, created_at) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            user_id,
+            library_name,
+            Utc::now().naive_utc()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn favorite_remove(&self, user_id: i64, library_name: &str) -> Result<(), E> {
+        let result = sqlx::query!(
+            "DELETE FROM favorite_libraries WHERE user_id = $1 AND library_name = $2",
+            user_id,
+            library_name
+        )
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::NotFound);
+        }
+
+        Ok(())
+    }
+
+    pub async fn favorite_list(&self, user_id: i64) -> Result<Favorites, E> {
+        let library_names = sqlx::query_scalar!(
+            "SELECT library_name FROM favorite_libraries WHERE user_id = $1 ORDER BY created_at, library_name",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(Favorites { library_names })
+    }
+
+    // expand special name @favorites in comma separated library names
+    pub async fn favorite_expand(
+        &self,
+        user_id: i64,
+        library_names: &str,
+    ) -> Result<Vec<String>, E> {
+        let mut result: Vec<String> = vec![];
+
+        for library_name in library_names.split(',') {
+            let expanded = match library_name {
+                FAVORITES => self.favorite_list(user_id).await?.library_names,
+                _ => vec![library_name.to_string()],
+            };
+
+            for library_name in expanded {
+                if !result.contains(&library_name) {
+                    result.push(library_name);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    // isbn is stored as normalized isbn-13, adding twice is no-op
+    pub async fn bookmark_add(&self, user_id: i64, isbn: &str) -> Result<(), E> {
+        let isbn = isbn::normalize(isbn).ok_or(Error::Validation("invalid isbn".to_string()))?;
+
+        sqlx::query!(
+            "INSERT INTO bookmarks (user_id, isbn, created_at) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            user_id,
+            isbn,
+            Utc::now().naive_utc()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn bookmark_remove(&self, user_id: i64, isbn: &str) -> Result<(), E> {
+        let isbn = isbn::normalize(isbn).ok_or(Error::Validation("invalid isbn".to_string()))?;
+
+        let result = sqlx::query!(
+            "DELETE FROM bookmarks WHERE user_id = $1 AND isbn = $2",
+            user_id,
+            isbn
+        )
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::NotFound);
+        }
+
+        Ok(())
+    }
+
+    // book is filled from cache, none when it is not cached yet
+    pub async fn bookmark_query(
+        &self,
+        user_id: i64,
+        page_size: u32,
+        page: u32,
+    ) -> Result<BookmarkChunk, E> {
+        let rows = sqlx::query!(
+            "SELECT isbn, created_at FROM bookmarks WHERE user_id = $1
+            ORDER BY created_at DESC, isbn OFFSET $2 LIMIT $3",
+            user_id,
+            page as i64 * page_size as i64,
+            page_size as i64
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut items = vec![];
+        for row in rows {
+            let book = self.book_get_cached(&row.isbn).await?;
+            items.push(Bookmark {
+                isbn: row.isbn,
+                created_at: row.created_at,
+                book,
+            });
+        }
+
+        let total_count = sqlx::query!("SELECT COUNT(*) FROM bookmarks WHERE user_id = $1", user_id)
+            .fetch_one(&self.pool)
+            .await?
+            .count
+            .ok_or(Error::Internal("failed to count".to_string()))?
+            as u32;
+
+        Ok(BookmarkChunk {
+            items,
+            total_count,
+            page_info: PageInfo::new(page, page_size, total_count),
+        })
+    }
+
+    pub async fn book_upsert(&self, book: &Book) -> Result<(), E> {
+        let isbn = book
+            .isbn
+            .as_deref()
+            .ok_or(Error::Validation("no isbn".to_string()))?;
+
+        // roles are kept as parallel columns, empty role is none
+        let creator_names: Vec<_> = book
+            .creator_roles
+            .iter()
+            .map(|creator| creator.name.clone())
+            .collect();
+        let creator_roles: Vec<_> = book
+            .creator_roles
+            .iter()
+            .map(|creator| creator.role.clone().unwrap_or_default())
+            .collect();
+
+        sqlx::query!(
+            "INSERT INTO books (isbn, title, descriptions, keywords, creators, publishers, issued_at, language, annotations, image_url, cached_at, source, volume, series_title, creator_names, creator_roles) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+            ON CONFLICT (isbn) DO UPDATE SET title = $2, descriptions = $3, keywords = $4, creators = $5, publishers = $6, issued_at = $7, language = $8, annotations = $9, image_url = $10, cached_at = $11, source = $12, volume = $13, series_title = $14, creator_names = $15, creator_roles = $16",
+            isbn,
+            book.title,
+            &book.descriptions[..],
+            &book.keywords[..],
+            &book.creators[..],
+            &book.publishers[..],
+            book.issued_at,
+            book.language,
+            &book.annotations[..],
+            book.image_url,
+            Utc::now().naive_utc(),
+            book.source,
+            book.volume,
+            book.series_title,
+            &creator_names[..],
+            &creator_roles[..]
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // insert new libraries and update changed ones, rows absent from a partial feed are kept
+    // return count of inserted or actually changed rows
+    pub async fn library_upsert_all(&self, libraries: &[Library]) -> Result<u64, E> {
+        let libraries: Vec<_> = libraries
+            .iter()
+            .filter(|library| library.system_id.is_some() && library.ingroup_id.is_some())
+            .collect();
+
+        // bind as column arrays to upsert in a single statement
+        let column = |f: fn(&Library) -> &Option<String>| -> Vec<String> {
+            libraries
+                .iter()
+                .map(|library| f(library).clone().unwrap_or_default())
+                .collect()
+        };
+        let system_ids = column(|library| &library.system_id);
+        let ingroup_ids = column(|library| &library.ingroup_id);
+        let addresses = column(|library| &library.address);
+        let prefectures = column(|library| &library.prefecture);
+        let cities = column(|library| &library.city);
+        let postcodes = column(|library| &library.postcode);
+        let tels = column(|library| &library.tel);
+        let urls = column(|library| &library.url);
+        let names: Vec<_> = libraries
+            .iter()
+            .map(|library| library.name.clone())
+            .collect();
+        let geocodes: Vec<_> = libraries
+            .iter()
+            .map(|library| library.geocode.unwrap_or_default())
+            .collect();
+        let latitudes: Vec<_> = geocodes.iter().map(|geocode| geocode.0).collect();
+        let longitudes: Vec<_> = geocodes.iter().map(|geocode| geocode.1).collect();
+
+        let result = sqlx::query!(
+            "INSERT INTO libraries (system_id, ingroup_id, name, address, prefecture, city, postcode, tel, url, latitude, longitude, updated_at)
+            SELECT *, $12::TIMESTAMP FROM UNNEST($1::VARCHAR[], $2::VARCHAR[], $3::TEXT[], $4::TEXT[], $5::TEXT[], $6::TEXT[], $7::TEXT[], $8::TEXT[], $9::TEXT[], $10::FLOAT8[], $11::FLOAT8[])
+            ON CONFLICT (system_id, ingroup_id) DO UPDATE SET name = EXCLUDED.name, address = EXCLUDED.address, prefecture = EXCLUDED.prefecture, city = EXCLUDED.city, postcode = EXCLUDED.postcode, tel = EXCLUDED.tel, url = EXCLUDED.url, latitude = EXCLUDED.latitude, longitude = EXCLUDED.longitude, updated_at = EXCLUDED.updated_at
+            WHERE (libraries.name, libraries.address, libraries.prefecture, libraries.city, libraries.postcode, libraries.tel, libraries.url, libraries.latitude, libraries.longitude)
+            IS DISTINCT FROM (EXCLUDED.name, EXCLUDED.address, EXCLUDED.prefecture, EXCLUDED.city, EXCLUDED.postcode, EXCLUDED.tel, EXCLUDED.url, EXCLUDED.latitude, EXCLUDED.longitude)",
+            &system_ids[..],
+            &ingroup_ids[..],
+            &names[..],
+            &addresses[..],
+            &prefectures[..],
+            &cities[..],
+            &postcodes[..],
+            &tels[..],
+            &urls[..],
+            &latitudes[..],
+            &longitudes[..],
+            Utc::now().naive_utc()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn book_get_cached(&self, isbn: &str) -> Result<Option<Book>, E> {
+        let book = sqlx::query!("SELECT * FROM books WHERE isbn = $1", isbn)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| Book {
+                title: row.title,
+                descriptions: row.descriptions,
+                keywords: row.keywords,
+                creators: row.creators,
+                publishers: row.publishers,
+                year: row.issued_at.as_deref().and_then(issued::year),
+                issued_at: row.issued_at,
+                isbn: Some(row.isbn),
+                language: row.language,
+                annotations: row.annotations,
+                image_url: row.image_url,
+                volume: row.volume,
+                series_title: row.series_title,
+                creator_roles: row
+                    .creator_names
+                    .into_iter()
+                    .zip(row.creator_roles)
+                    .map(|(name, role)| Creator {
+                        name,
+                        role: Some(role).filter(|role| !role.is_empty()),
+                    })
+                    .collect(),
+                source: row.source,
+                ..Default::default()
+            });
+
+        Ok(book)
+    }
+}
+
+// taken before query and cut to the precision of database,
+// so a change committed during the query is sent again next time rather than missed
+fn server_time() -> NaiveDateTime {
+    let now = Utc::now().naive_utc();
+    DateTime::from_timestamp_micros(now.and_utc().timestamp_micros())
+        .map(|time| time.naive_utc())
+        .unwrap_or(now)
+}
+
+fn token_generate() -> String {
+    let mut buf = [0u8; 32];
+    rand::rngs::OsRng.fill(&mut buf);
+    base64::engine::general_purpose::STANDARD.encode(buf)
+}
+
+// reserves of one book at one library form a queue, changes of it are serialized
+// so that positions are counted from a settled queue
+async fn queue_lock(
+    tx: &mut Transaction<'_, Postgres>,
+    isbn: &str,
+    library_name: &str,
+) -> Result<(), E> {
+    sqlx::query!(
+        "SELECT 1 AS locked FROM pg_advisory_xact_lock(hashtext($1::VARCHAR || '/' || $2::VARCHAR))",
+        isbn,
+        library_name
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+// queue is keyed by isbn-13 so that any form of the same book joins it,
+// isbn which is not checked is kept as given
+fn reserve_isbn(isbn: &str) -> String {
+    isbn::normalize(isbn).unwrap_or_else(|| isbn.to_string())
+}
+
+// row lock of the user serializes creates of the user, so that the cap holds
+// and the same book is not reserved twice at the library
+async fn reserve_create_check(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i64,
+    isbn: &str,
+    library_name: &str,
+    max_active_reserves: u32,
+) -> Result<(), E> {
+    sqlx::query!("SELECT id FROM users WHERE id = $1 FOR UPDATE", user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let count = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM reserves WHERE user_id = $1 AND state NOT IN ('Completed', 'Cancelled')",
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if count.unwrap_or_default() as u32 >= max_active_reserves {
+        return Err(Error::ReserveLimitReached(max_active_reserves));
+    }
+
+    let exists = sqlx::query_scalar!(
+        "SELECT EXISTS (SELECT 1 FROM reserves WHERE user_id = $1 AND isbn = $2 AND library_name = $3 AND state NOT IN ('Completed', 'Cancelled'))",
+        user_id,
+        isbn,
+        library_name,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if exists.unwrap_or(false) {
+        return Err(Error::Conflict("reserve already exists".to_string()));
+    }
+
+    Ok(())
+}
+
+// lock queue of the reserve, reserve of another user is not found
+async fn reserve_lock(tx: &mut Transaction<'_, Postgres>, user_id: i64, id: i64) -> Result<(), E> {
+    let reserve = sqlx::query!(
+        "SELECT isbn, library_name FROM reserves WHERE id = $1 AND user_id = $2",
+        id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    queue_lock(tx, &reserve.isbn, &reserve.library_name).await
+}
+
+// number active reserves of the book at the library in staging order
+// only reserves which moved are marked as updated and returned
+async fn reserve_requeue(
+    tx: &mut Transaction<'_, Postgres>,
+    isbn: &str,
+    library_name: &str,
+) -> Result<Vec<Reserve>, E> {
+    let ids = sqlx::query_scalar!(
+        "UPDATE reserves SET queue_position = queue.position, updated_at = $3
+        FROM (
+            SELECT id, ROW_NUMBER() OVER (ORDER BY staging_at, id)::INTEGER AS position
+            FROM reserves WHERE isbn = $1 AND library_name = $2 AND state NOT IN ('Completed', 'Cancelled')
+        ) AS queue
+        WHERE reserves.id = queue.id AND reserves.queue_position IS DISTINCT FROM queue.position
+        RETURNING reserves.id",
+        isbn,
+        library_name,
+        Utc::now().naive_utc()
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let moved = sqlx::query_as!(
+        Reserve,
+        "SELECT * FROM reserves WHERE id = ANY($1) ORDER BY queue_position",
+        &ids
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    Ok(moved)
+}
+
+// call connect until it succeeds or attempts run out, last error is returned
+async fn connect_retry<T, Er, F, Fut>(
+    attempts: u32,
+    backoff: Duration,
+    mut connect: F,
+) -> Result<T, Er>
+where
+    Er: std::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Er>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        match connect().await {
+            Err(err) if attempt < attempts => {
+                let wait = backoff.saturating_mul(2u32.saturating_pow(attempt - 1));
+                eprintln!("warn: database connect attempt {attempt} failed: {err}");
+                attempt += 1;
+                actix_web::rt::time::sleep(wait).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect_retry, server_time, Entity, PoolConfig};
+    use crate::error::Error;
+    use crate::models::{
+        Book, Creator, Favorites, HolderState, Library, Reserve, ReserveCursor, ReserveFilter,
+    };
+    use std::{
+        env,
+        time::{Duration, Instant},
+    };
+
+    #[actix_web::test]
+    async fn test_pool_config() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let config = PoolConfig {
+            max_connections: 2,
+            ..Default::default()
+        };
+        let app = Entity::with_pool_config(&appkey, &config).await.unwrap();
+
+        // 4 queries on 2 connections run in 2 rounds
+        let start = Instant::now();
+        let tasks = (0..4).map(|_| sqlx::query("SELECT pg_sleep(0.2)").execute(&app.pool));
+        let results = futures::future::join_all(tasks).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert!(app.pool.size() <= 2);
+    }
+
+    #[actix_web::test]
+    async fn test_connect_retry() {
+        let mut calls = 0;
+        let start = Instant::now();
+        let result = connect_retry(5, Duration::from_millis(10), || {
+            calls += 1;
+            let result = match calls {
+                1 | 2 => Err("connection refused"),
+                _ => Ok(calls),
+            };
+            async move { result }
+        })
+        .await;
+        assert_eq!(result, Ok(3));
+        // 10ms then 20ms between attempts
+        assert!(start.elapsed() >= Duration::from_millis(30));
+
+        // gives up after attempts with the last error
+        let mut calls = 0;
+        let result: Result<(), _> = connect_retry(2, Duration::ZERO, || {
+            calls += 1;
+            async { Err("connection refused") }
+        })
+        .await;
+        assert_eq!(result, Err("connection refused"));
+        assert_eq!(calls, 2);
+
+        // unreachable db fails after attempts instead of hanging
+        let config = PoolConfig {
+            connect_attempts: 2,
+            connect_backoff: Duration::from_millis(10),
+            acquire_timeout: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let result =
+            Entity::with_pool_config("postgres://postgres@127.0.0.1:1/libres", &config).await;
+        assert!(result.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_migrate() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let admin = Entity::new(&appkey).await.unwrap();
+
+        let name = format!("test_migrate_{}", rand::random::<u32>());
+        sqlx::query(&format!("CREATE DATABASE {name}"))
+            .execute(&admin.pool)
+            .await
+            .unwrap();
+
+        let (base, _) = appkey.rsplit_once('/').unwrap();
+        let app = Entity::new(&format!("{base}/{name}")).await.unwrap();
+
+        let versions = app.migrate().await.unwrap();
+        assert!(!versions.is_empty());
+        let tables: Vec<String> = sqlx::query_scalar(
+            "SELECT table_name::TEXT FROM information_schema.tables WHERE table_schema = 'public'",
+        )
+        .fetch_all(&app.pool)
+        .await
+        .unwrap();
+        for table in ["users", "sessions", "reserves", "books", "libraries"] {
+            assert!(
+                tables.iter().any(|name| name == table),
+                "{table} is missing"
+            );
+        }
+
+        // second run is no-op
+        assert!(app.migrate().await.unwrap().is_empty());
+
+        app.pool.close().await;
+        sqlx::query(&format!("DROP DATABASE {name}"))
+            .execute(&admin.pool)
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_user_create() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+        app.user_create("alice@example2.com", "alice", "アリス", "日本")
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_entity() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let token = app.user_login("alice@example2.com", "alice").await.unwrap();
+        println!("token: {token:?}");
+
+        let user = app.user_get(&token).await.unwrap();
+        println!("user get: {user:?}");
+
+        let reserves = app
+            .reserve_query(user.id, 20, 0, &Default::default())
+            .await
+            .unwrap();
+        println!("reserves query: {reserves:?}");
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_filter() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        // shared user piles up reserves across runs
+        let app = Entity::new(&appkey)
+            .await
+            .unwrap()
+            .with_max_active_reserves(u32::MAX);
+
+        let token = app.user_login("alice@example2.com", "alice").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+
+        let library_name = format!("フィルタ市立図書館{}", rand::random::<u32>());
+        app.reserve_create(user.id, "9784001141276", &library_name, None)
+            .await
+            .unwrap();
+
+        let filter = ReserveFilter {
+            state: Some("Staging".to_string()),
+            ..Default::default()
+        };
+        let reserves = app.reserve_query(user.id, 100, 0, &filter).await.unwrap();
+        assert!(!reserves.items.is_empty());
+        assert!(reserves.items.iter().all(|item| item.state == "Staging"));
+
+        let filter = ReserveFilter {
+            state: Some("Nonexistent".to_string()),
+            ..Default::default()
+        };
+        let reserves = app.reserve_query(user.id, 100, 0, &filter).await.unwrap();
+        assert!(reserves.items.is_empty());
+        assert_eq!(reserves.total_count, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_modified_since() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let email = format!("sync-{}@example2.com", rand::random::<u32>());
+        app.user_create(&email, "sync", "シンク", "日本")
+            .await
+            .unwrap();
+        let token = app.user_login(&email, "sync").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+
+        for isbn in ["9784001141276", "9784798121963"] {
+            app.reserve_create(user.id, isbn, "富山県立大学附属図書館射水館", None)
+                .await
+                .unwrap();
+        }
+        let filter = ReserveFilter::default();
+        let all = app.reserve_query(user.id, 100, 0, &filter).await.unwrap();
+        assert_eq!(all.items.len(), 2);
+
+        let filter = ReserveFilter {
+            modified_since: Some(all.server_time),
+            ..Default::default()
+        };
+        let res = app.reserve_query(user.id, 100, 0, &filter).await.unwrap();
+        assert!(res.items.is_empty());
+        assert!(res.server_time >= all.server_time);
+
+        let advanced = app.reserve_advance(user.id, all.items[1].id).await.unwrap();
+        assert_eq!(advanced.state, "Staged");
+        assert!(advanced.updated_at >= all.server_time);
+
+        let res = app.reserve_query(user.id, 100, 0, &filter).await.unwrap();
+        let ids: Vec<_> = res.items.iter().map(|item| item.id).collect();
+        assert_eq!(ids, vec![advanced.id]);
+        assert_eq!(res.total_count, 1);
+
+        let filter = ReserveFilter {
+            modified_since: Some(res.server_time),
+            ..Default::default()
+        };
+        let res = app.reserve_query(user.id, 100, 0, &filter).await.unwrap();
+        assert!(res.items.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_cursor() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let email = format!("cursor-{}@example2.com", rand::random::<u32>());
+        app.user_create(&email, "cursor", "カーソル", "日本")
+            .await
+            .unwrap();
+        let token = app.user_login(&email, "cursor").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+
+        // same book is reserved once per library
+        let library_names: Vec<_> = (0..6).map(|i| format!("カーソル図書館{i}")).collect();
+        let create =
+            |i: usize| app.reserve_create(user.id, "9784001141276", &library_names[i], None);
+        for i in 0..5 {
+            create(i).await.unwrap();
+        }
+        let filter = ReserveFilter::default();
+        let all = app.reserve_query(user.id, 100, 0, &filter).await.unwrap();
+        let ids = |items: &[Reserve]| items.iter().map(|item| item.id).collect::<Vec<_>>();
+
+        let first = app.reserve_query(user.id, 2, 0, &filter).await.unwrap();
+        assert_eq!(ids(&first.items), ids(&all.items[..2]));
+        let cursor = first.next_cursor.unwrap();
+
+        // new reserve comes before cursor, so it is neither skipped into nor duplicated
+        create(5).await.unwrap();
+        let second = app
+            .reserve_query_after(user.id, 2, &cursor, &filter)
+            .await
+            .unwrap();
+        assert_eq!(ids(&second.items), ids(&all.items[2..4]));
+        assert_eq!(second.total_count, 6);
+        assert!(second.page_info.has_next);
+
+        let third = app
+            .reserve_query_after(user.id, 2, &second.next_cursor.unwrap(), &filter)
+            .await
+            .unwrap();
+        assert_eq!(ids(&third.items), ids(&all.items[4..]));
+        assert!(third.next_cursor.is_none());
+        assert!(!third.page_info.has_next);
+
+        // offset paging shifts by the inserted reserve
+        let shifted = app.reserve_query(user.id, 2, 1, &filter).await.unwrap();
+        assert_eq!(shifted.items[0].id, all.items[1].id);
+
+        // cursor survives round trip as text
+        let text = String::from(cursor);
+        assert_eq!(ReserveCursor::try_from(text).unwrap(), cursor);
+        assert!(ReserveCursor::try_from("page2".to_string()).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_queue() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let mut users = vec![];
+        for _ in 0..2 {
+            let email = format!("queue-{}@example2.com", rand::random::<u32>());
+            app.user_create(&email, "queue", "キュー", "日本")
+                .await
+                .unwrap();
+            let token = app.user_login(&email, "queue").await.unwrap();
+            users.push(app.user_get(&token).await.unwrap());
+        }
+
+        // queue is per book and library, other tests reserve the same book
+        let library_name = format!("キュー市立図書館{}", rand::random::<u32>());
+        let mut events = app.reserve_subscribe();
+        let mut reserves = vec![];
+        for user in &users {
+            app.reserve_create(user.id, "9784001141276", &library_name, None)
+                .await
+                .unwrap();
+            reserves.push(events.recv().await.unwrap());
+        }
+        assert_eq!(reserves[0].queue_position, Some(1));
+        assert_eq!(reserves[1].queue_position, Some(2));
+
+        // user queues once for the book at the library
+        let err = app
+            .reserve_create(users[0].id, "9784001141276", &library_name, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Conflict(_)));
+
+        // other forms of the isbn are the same book
+        let err = app
+            .reserve_create(users[0].id, "978-4-00-114127-6", &library_name, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Conflict(_)));
+        let err = app
+            .reserve_create_allowed(users[1].id, "4001141272", &library_name)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Conflict(_)));
+
+        // cancelling the first promotes the second, both are notified
+        let since = server_time();
+        app.reserve_cancel(users[0].id, reserves[0].id)
+            .await
+            .unwrap();
+        let cancelled = events.recv().await.unwrap();
+        assert_eq!(cancelled.id, reserves[0].id);
+        assert_eq!(cancelled.state, "Cancelled");
+        assert_eq!(cancelled.queue_position, None);
+        let promoted = events.recv().await.unwrap();
+        assert_eq!(promoted.id, reserves[1].id);
+        assert_eq!(promoted.queue_position, Some(1));
+        let second = app.reserve_get(users[1].id, reserves[1].id).await.unwrap();
+        assert_eq!(second.queue_position, Some(1));
+
+        // cancelled reserve is kept for delta sync, hidden from plain listing
+        let filter = ReserveFilter {
+            modified_since: Some(since),
+            ..Default::default()
+        };
+        let changed = app
+            .reserve_query(users[0].id, 10, 0, &filter)
+            .await
+            .unwrap();
+        assert_eq!(changed.items.len(), 1);
+        assert_eq!(changed.items[0].state, "Cancelled");
+        let listed = app
+            .reserve_query(users[0].id, 10, 0, &ReserveFilter::default())
+            .await
+            .unwrap();
+        assert!(listed.items.iter().all(|item| item.id != reserves[0].id));
+        assert!(app
+            .reserve_cancel(users[0].id, reserves[0].id)
+            .await
+            .is_err());
+
+        // only owner can cancel
+        assert!(app
+            .reserve_cancel(users[0].id, reserves[1].id)
+            .await
+            .is_err());
+
+        // completed reserve leaves the queue
+        app.reserve_create(users[0].id, "9784001141276", &library_name, None)
+            .await
+            .unwrap();
+        let third = events.recv().await.unwrap();
+        assert_eq!(third.queue_position, Some(2));
+        for _ in 0..3 {
+            app.reserve_advance(users[1].id, reserves[1].id)
+                .await
+                .unwrap();
+        }
+        let second = app.reserve_get(users[1].id, reserves[1].id).await.unwrap();
+        assert_eq!(second.queue_position, None);
+        let third = app.reserve_get(users[0].id, third.id).await.unwrap();
+        assert_eq!(third.queue_position, Some(1));
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_summary() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let email = format!("summary-{}@example2.com", rand::random::<u32>());
+        app.user_create(&email, "summary", "サマリー", "日本")
+            .await
+            .unwrap();
+        let token = app.user_login(&email, "summary").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+
+        assert!(app.reserve_summary(user.id).await.unwrap().is_empty());
+
+        for isbn in ["9784001141276", "9784834000825", "9784798121963"] {
+            app.reserve_create(user.id, isbn, "富山県立大学附属図書館射水館", None)
+                .await
+                .unwrap();
+        }
+        sqlx::query(
+            "UPDATE reserves SET state = 'Completed' WHERE id = (SELECT MIN(id) FROM reserves WHERE user_id = $1)",
+        )
+        .bind(user.id)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+
+        let summary = app.reserve_summary(user.id).await.unwrap();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary["Staging"], 2);
+        assert_eq!(summary["Completed"], 1);
+
+        let active = app.reserve_query_active(user.id).await.unwrap();
+        assert_eq!(active.len(), 2);
+        assert!(active.iter().all(|item| item.state == "Staging"));
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_limit() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey)
+            .await
+            .unwrap()
+            .with_max_active_reserves(2);
+
+        let email = format!("limit-{}@example2.com", rand::random::<u32>());
+        app.user_create(&email, "limit", "リミット", "日本")
+            .await
+            .unwrap();
+        let token = app.user_login(&email, "limit").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+
+        let library_name = format!("リミット市立図書館{}", rand::random::<u32>());
+        let mut events = app.reserve_subscribe();
+        let mut reserves = vec![];
+        for isbn in ["9784001141276", "9784834000825"] {
+            app.reserve_create(user.id, isbn, &library_name, None)
+                .await
+                .unwrap();
+            reserves.push(events.recv().await.unwrap());
+        }
+        assert_eq!(app.reserve_active_count(user.id).await.unwrap(), 2);
+
+        let err = app
+            .reserve_create(user.id, "9784798121963", &library_name, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ReserveLimitReached(2)));
+        let err = app
+            .reserve_create_allowed(user.id, "9784798121963", &library_name)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ReserveLimitReached(2)));
+
+        // cancelled and completed reserves free a slot
+        app.reserve_cancel(user.id, reserves[0].id).await.unwrap();
+        app.reserve_create(user.id, "9784798121963", &library_name, None)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            app.reserve_advance(user.id, reserves[1].id).await.unwrap();
+        }
+        app.reserve_create(user.id, "9784001141276", &library_name, None)
+            .await
+            .unwrap();
+        assert!(app
+            .reserve_create(user.id, "9784834000825", &library_name, None)
+            .await
+            .is_err());
+
+        // concurrent creates do not pass the cap together
+        let email = format!("limit-{}@example2.com", rand::random::<u32>());
+        app.user_create(&email, "limit", "リミット", "日本")
+            .await
+            .unwrap();
+        let token = app.user_login(&email, "limit").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+        let creates = ["9784001141276", "9784834000825", "9784798121963"]
+            .map(|isbn| app.reserve_create(user.id, isbn, &library_name, None));
+        let created = futures::future::join_all(creates)
+            .await
+            .into_iter()
+            .filter(Result::is_ok)
+            .count();
+        assert_eq!(created, 2);
+        assert_eq!(app.reserve_active_count(user.id).await.unwrap(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_advance() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let email = format!("advance-{}@example2.com", rand::random::<u32>());
+        app.user_create(&email, "advance", "アドバンス", "日本")
+            .await
+            .unwrap();
+        let token = app.user_login(&email, "advance").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+
+        let mut events = app.reserve_subscribe();
+        app.reserve_create(
+            user.id,
+            "9784001141276",
+            "富山県立大学附属図書館射水館",
+            None,
+        )
+        .await
+        .unwrap();
+        let created = events.recv().await.unwrap();
+        assert_eq!(created.state, "Staging");
+
+        for state in ["Staged", "Reserved", "Completed"] {
+            let reserve = app.reserve_advance(user.id, created.id).await.unwrap();
+            assert_eq!(reserve.state, state);
+            assert_eq!(events.recv().await.unwrap().state, state);
+        }
+        let reserve = app.reserve_get(user.id, created.id).await.unwrap();
+        assert!(reserve.staged_at.is_some());
+        assert!(reserve.reserved_at.is_some());
+        assert!(reserve.completed_at.is_some());
+
+        // completed reserve and reserve of other user do not move
+        assert!(app.reserve_advance(user.id, created.id).await.is_err());
+        assert!(app.reserve_advance(user.id + 1, created.id).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_ready() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let mut users = vec![];
+        for _ in 0..2 {
+            let email = format!("ready-{}@example2.com", rand::random::<u32>());
+            app.user_create(&email, "ready", "レディ", "日本")
+                .await
+                .unwrap();
+            let token = app.user_login(&email, "ready").await.unwrap();
+            users.push(app.user_get(&token).await.unwrap());
+        }
+
+        let library_name = format!("レディ市立図書館{}", rand::random::<u32>());
+        let mut events = app.reserve_subscribe();
+        let mut reserves = vec![];
+        for user in &users {
+            app.reserve_create(user.id, "9784001141276", &library_name, None)
+                .await
+                .unwrap();
+            reserves.push(events.recv().await.unwrap());
+        }
+
+        // not reserved yet, then only the first of the queue gets the copy
+        assert!(app
+            .reserve_ready(reserves[0].user_id, reserves[0].id)
+            .await
+            .is_err());
+        for reserve in &reserves {
+            for _ in 0..2 {
+                app.reserve_advance(reserve.user_id, reserve.id)
+                    .await
+                    .unwrap();
+            }
+        }
+        assert!(app
+            .reserve_ready(reserves[1].user_id, reserves[1].id)
+            .await
+            .is_err());
+        let ready = app
+            .reserve_ready(reserves[0].user_id, reserves[0].id)
+            .await
+            .unwrap();
+        assert_eq!(ready.state, "Ready");
+        assert!(ready.ready_at.is_some());
+        assert_eq!(ready.queue_position, Some(1));
+
+        // ready reserve is completed as usual
+        let completed = app.reserve_advance(ready.user_id, ready.id).await.unwrap();
+        assert_eq!(completed.state, "Completed");
+        assert!(completed.completed_at.is_some());
+        assert_eq!(completed.queue_position, None);
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_history() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let email = format!("history-{}@example2.com", rand::random::<u32>());
+        app.user_create(&email, "history", "ヒストリー", "日本")
+            .await
+            .unwrap();
+        let token = app.user_login(&email, "history").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+
+        app.reserve_create(
+            user.id,
+            "9784001141276",
+            "富山県立大学附属図書館射水館",
+            None,
+        )
+        .await
+        .unwrap();
+        let reserve = app.reserve_query_all(user.id).await.unwrap().remove(0);
+        assert!(app
+            .reserve_history(user.id, reserve.id)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let states = [
+            HolderState::Borrowed,
+            HolderState::Borrowed,
+            HolderState::Unknown,
+            HolderState::Reservable,
+        ];
+        for state in &states {
+            app.holder_snapshot_add(reserve.id, state).await.unwrap();
+        }
+
+        // failed check is not recorded, so it does not hide the previous state
+        app.holder_snapshot_add(reserve.id, &HolderState::Unknown)
+            .await
+            .unwrap();
+        let latest = app.holder_snapshot_latest(reserve.id).await.unwrap();
+        assert_eq!(latest, Some(HolderState::Reservable));
+
+        let history = app.reserve_history(user.id, reserve.id).await.unwrap();
+        let names: Vec<_> = history.iter().map(|item| item.state.as_str()).collect();
+        assert_eq!(names, vec!["Borrowed", "Borrowed", "Reservable"]);
+        assert!(history
+            .windows(2)
+            .all(|pair| pair[0].checked_at <= pair[1].checked_at));
+        assert!(history[0].checked_at >= reserve.staging_at);
+
+        // reserve of other user is not shown
+        assert!(app.reserve_history(user.id + 1, reserve.id).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_book_cache() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let isbn = format!("test-{}", rand::random::<u32>());
+
+        let cached = app.book_get_cached(&isbn).await.unwrap();
+        assert!(cached.is_none());
+
+        let book = Book {
+            title: "ドメイン駆動設計".to_string(),
+            creators: vec!["Evans,Eric".to_string(), "今関剛 訳".to_string()],
+            creator_roles: vec![
+                Creator {
+                    name: "Evans,Eric".to_string(),
+                    role: None,
+                },
+                Creator {
+                    name: "今関剛".to_string(),
+                    role: Some("訳".to_string()),
+                },
+            ],
+            isbn: Some(isbn.clone()),
+            volume: Some("上".to_string()),
+            series_title: Some("IT Architects' Archive".to_string()),
+            source: Some("ndl".to_string()),
+            ..Default::default()
+        };
+        app.book_upsert(&book).await.unwrap();
+
+        // every field given back by a backend survives the cache
+        let cached = app.book_get_cached(&isbn).await.unwrap().unwrap();
+        assert_eq!(cached.title, book.title);
+        assert_eq!(cached.creators, book.creators);
+        assert_eq!(cached.creator_roles, book.creator_roles);
+        assert_eq!(cached.volume, book.volume);
+        assert_eq!(cached.series_title, book.series_title);
+        assert_eq!(cached.source, book.source);
+    }
+
+    #[actix_web::test]
+    async fn test_library_upsert_all() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let system_id = format!("Test_{}", rand::random::<u32>());
+        let libraries: Vec<_> = ["1", "2"]
+            .iter()
+            .map(|ingroup_id| Library {
+                name: format!("テスト図書館{ingroup_id}"),
+                system_id: Some(system_id.clone()),
+                ingroup_id: Some(ingroup_id.to_string()),
+                geocode: Some((35.0, 139.0)),
+                ..Default::default()
+            })
+            .collect();
+
+        let xmin = || async {
+            sqlx::query_scalar::<_, String>(
+                "SELECT xmin::TEXT FROM libraries WHERE system_id = $1 ORDER BY ingroup_id",
+            )
+            .bind(&system_id)
+            .fetch_all(&app.pool)
+            .await
+            .unwrap()
+        };
+
+        assert_eq!(app.library_upsert_all(&libraries).await.unwrap(), 2);
+        let before = xmin().await;
+
+        // unchanged set touches no row
+        assert_eq!(app.library_upsert_all(&libraries).await.unwrap(), 0);
+        assert_eq!(xmin().await, before);
+
+        // partial feed updates changed row and keeps the absent one
+        let mut changed = libraries[0].clone();
+        changed.tel = Some("03-0000-0000".to_string());
+        assert_eq!(app.library_upsert_all(&[changed]).await.unwrap(), 1);
+        assert_eq!(xmin().await.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_favorite() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let token = app.user_login("alice@example2.com", "alice").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+
+        let library_name = format!("テスト図書館{}", rand::random::<u32>());
+        app.favorite_add(user.id, &library_name).await.unwrap();
+        app.favorite_add(user.id, &library_name).await.unwrap();
+
+        let favorites = app.favorite_list(user.id).await.unwrap();
+        let count = |favorites: &Favorites| {
+            favorites
+                .library_names
+                .iter()
+                .filter(|name| **name == library_name)
+                .count()
+        };
+        assert_eq!(count(&favorites), 1);
+
+        let expanded = app
+            .favorite_expand(user.id, &format!("他の図書館,@favorites,{library_name}"))
+            .await
+            .unwrap();
+        assert_eq!(expanded[0], "他の図書館");
+        assert_eq!(expanded.len(), favorites.library_names.len() + 1);
+        assert!(expanded.contains(&library_name));
+
+        app.favorite_remove(user.id, &library_name).await.unwrap();
+        assert!(app.favorite_remove(user.id, &library_name).await.is_err());
+
+        let favorites = app.favorite_list(user.id).await.unwrap();
+        assert_eq!(count(&favorites), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_bookmark() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let token = app.user_login("alice@example2.com", "alice").await.unwrap();
+        let user = app.user_get(&token).await.unwrap();
+
+        let book = Book {
+            title: "ドメイン駆動設計".to_string(),
+            isbn: Some("9784798121963".to_string()),
+            ..Default::default()
+        };
+        app.book_upsert(&book).await.unwrap();
+
+        // hyphenated isbn-10 is the same book
+        app.bookmark_add(user.id, "9784798121963").await.unwrap();
+        app.bookmark_add(user.id, "4-7981-2196-7").await.unwrap();
+        assert!(app.bookmark_add(user.id, "invalid").await.is_err());
+
+        let chunk = app.bookmark_query(user.id, 100, 0).await.unwrap();
+        let items: Vec<_> = chunk
+            .items
+            .iter()
+            .filter(|item| item.isbn == "9784798121963")
+            .collect();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].book.as_ref().unwrap().title, book.title);
+
+        app.bookmark_remove(user.id, "978-4-7981-2196-3")
+            .await
+            .unwrap();
+        assert!(app.bookmark_remove(user.id, "9784798121963").await.is_err());
+
+        let after = app.bookmark_query(user.id, 100, 0).await.unwrap();
+        assert_eq!(after.total_count + 1, chunk.total_count);
+    }
+
+    #[actix_web::test]
+    async fn test_password_reset() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let email = format!("reset-{}@example2.com", rand::random::<u32>());
+        app.user_create(&email, "before", "リセット", "日本")
+            .await
+            .unwrap();
+
+        // happy path, token is single-use
+        let token = app.request_password_reset(&email).await.unwrap().unwrap();
+        app.confirm_password_reset(&token, "after").await.unwrap();
+        assert!(app.user_login(&email, "before").await.is_err());
+        assert!(app.user_login(&email, "after").await.is_ok());
+        assert!(app.confirm_password_reset(&token, "again").await.is_err());
+
+        // expired token
+        let token = app.request_password_reset(&email).await.unwrap().unwrap();
+        sqlx::query(
+            "UPDATE password_resets SET expires_at = NOW() - INTERVAL '1 minute' WHERE token = $1",
+        )
+        .bind(&token)
+        .execute(&app.pool)
+        .await
+        .unwrap();
+        assert!(app.confirm_password_reset(&token, "expired").await.is_err());
+
+        // unknown email gets no token
+        let token = app
+            .request_password_reset("nobody@example2.com")
+            .await
+            .unwrap();
+        assert!(token.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_verify_email() {
+        let appkey = env::var("DATABASE_URL").unwrap();
+        let app = Entity::new(&appkey).await.unwrap();
+
+        let email = format!("verify-{}@example2.com", rand::random::<u32>());
+        app.user_create(&email, "verify", "ベリファイ", "日本")
+            .await
+            .unwrap();
+
+        let token = app.user_login(&email, "verify").await.unwrap();
+        assert!(!app.user_get(&token).await.unwrap().email_verified);
+
+        let verification = app.issue_verification(&email).await.unwrap();
+        app.verify_email(&verification).await.unwrap();
+        assert!(app.user_get(&token).await.unwrap().email_verified);
+
+        // token is single-use
+        assert!(app.verify_email(&verification).await.is_err());
+        assert!(app.verify_email("invalid").await.is_err());
+        assert!(app.issue_verification("nobody@example2.com").await.is_err());
     }
 }
@@ -0,0 +1,208 @@
+use actix_web::http::StatusCode;
+use awc::error::{FreezeRequestError, PayloadError, SendRequestError};
+
+// failure of backends and entity, handlers map its kind to status
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("upstream {0}")]
+    Upstream(#[from] Upstream),
+    // upstream answered something other than expected document
+    #[error("malformed upstream response: {0}")]
+    Parse(String),
+    // no matching record, which is not a failure of upstream
+    #[error("not found")]
+    NotFound,
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("database: {0}")]
+    Db(sqlx::Error),
+    #[error("config: {0}")]
+    Config(String),
+    // user supplied value which is not sent to upstream nor stored
+    #[error("invalid: {0}")]
+    Validation(String),
+    // record already exists, e.g. email of another user
+    #[error("conflict: {0}")]
+    Conflict(String),
+    // user already has as many active reserves as allowed
+    #[error("reserve limit reached: {0}")]
+    ReserveLimitReached(u32),
+    // client calls again sooner than allowed
+    #[error("too many requests")]
+    TooManyRequests,
+    #[error("internal: {0}")]
+    Internal(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Upstream {
+    #[error("is busy")]
+    Busy,
+    // circuit breaker is open
+    #[error("is unavailable")]
+    Unavailable,
+    // upstream rejected api key for rate or quota limit
+    #[error("quota exhausted: {0}")]
+    Quota(String),
+    #[error("request failed: {0}")]
+    Request(String),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::Upstream(Upstream::Busy | Upstream::Unavailable) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::Upstream(_) | Error::Parse(_) => StatusCode::BAD_GATEWAY,
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Validation(_) => StatusCode::BAD_REQUEST,
+            Error::Conflict(_) | Error::ReserveLimitReached(_) => StatusCode::CONFLICT,
+            Error::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            Error::Db(_) | Error::Config(_) | Error::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    // whether upstream is to blame, counted in failure metrics
+    pub fn is_upstream(&self) -> bool {
+        matches!(self, Error::Upstream(_) | Error::Parse(_))
+    }
+
+    pub fn poisoned<T>(_: T) -> Self {
+        Error::Internal("poisoned".to_string())
+    }
+}
+
+// no row is the same as no record
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::RowNotFound => Error::NotFound,
+            err => Error::Db(err),
+        }
+    }
+}
+
+impl From<sqlx::migrate::MigrateError> for Error {
+    fn from(err: sqlx::migrate::MigrateError) -> Self {
+        Error::Config(format!("failed to migrate database: {err}"))
+    }
+}
+
+impl From<SendRequestError> for Error {
+    fn from(err: SendRequestError) -> Self {
+        Error::Upstream(Upstream::Request(err.to_string()))
+    }
+}
+
+impl From<FreezeRequestError> for Error {
+    fn from(err: FreezeRequestError) -> Self {
+        Error::Upstream(Upstream::Request(err.to_string()))
+    }
+}
+
+impl From<PayloadError> for Error {
+    fn from(err: PayloadError) -> Self {
+        Error::Upstream(Upstream::Request(err.to_string()))
+    }
+}
+
+impl From<serde_urlencoded::ser::Error> for Error {
+    fn from(err: serde_urlencoded::ser::Error) -> Self {
+        Error::Upstream(Upstream::Request(err.to_string()))
+    }
+}
+
+impl From<roxmltree::Error> for Error {
+    fn from(err: roxmltree::Error) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(err: std::str::Utf8Error) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+// reading body as text, e.g. invalid utf-8
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Error, Upstream};
+    use actix_web::http::StatusCode;
+
+    #[test]
+    fn test_status() {
+        let cases = [
+            (
+                Error::Upstream(Upstream::Busy),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                Error::Upstream(Upstream::Unavailable),
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                Error::Upstream(Upstream::Quota("limit".to_string())),
+                StatusCode::BAD_GATEWAY,
+            ),
+            (
+                Error::Upstream(Upstream::Request("reset".to_string())),
+                StatusCode::BAD_GATEWAY,
+            ),
+            (Error::Parse("eof".to_string()), StatusCode::BAD_GATEWAY),
+            (Error::NotFound, StatusCode::NOT_FOUND),
+            (Error::Conflict("email".to_string()), StatusCode::CONFLICT),
+            (Error::ReserveLimitReached(20), StatusCode::CONFLICT),
+            (Error::TooManyRequests, StatusCode::TOO_MANY_REQUESTS),
+            (
+                Error::Unauthorized("token".to_string()),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                Error::Db(sqlx::Error::PoolTimedOut),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                Error::Config("url".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                Error::Validation("isbn".to_string()),
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                Error::Internal("poisoned".to_string()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (err, status) in cases {
+            assert_eq!(err.status(), status, "{err}");
+        }
+
+        // conversions keep the kind
+        assert!(matches!(
+            Error::from(sqlx::Error::RowNotFound),
+            Error::NotFound
+        ));
+        let err = roxmltree::Document::parse("<a>").unwrap_err();
+        assert!(matches!(Error::from(err), Error::Parse(_)));
+        let err = serde_json::from_str::<u32>("x").unwrap_err();
+        assert!(matches!(Error::from(err), Error::Parse(_)));
+    }
+}
@@ -0,0 +1,92 @@
+use crate::models::Reserve;
+use chrono::NaiveDateTime;
+
+const RESERVE_HEADER: [&str; 9] = [
+    "id",
+    "isbn",
+    "library_name",
+    "state",
+    "staging_at",
+    "staged_at",
+    "reserved_at",
+    "completed_at",
+    "title",
+];
+
+// serialize reserves as rfc 4180 csv
+pub fn reserves_to_csv(reserves: &[Reserve]) -> String {
+    let mut text = csv_line(RESERVE_HEADER.iter().map(|field| field.to_string()));
+
+    for reserve in reserves {
+        text += &csv_line([
+            reserve.id.to_string(),
+            reserve.isbn.clone(),
+            reserve.library_name.clone(),
+            reserve.state.clone(),
+            format_datetime(Some(reserve.staging_at)),
+            format_datetime(reserve.staged_at),
+            format_datetime(reserve.reserved_at),
+            format_datetime(reserve.completed_at),
+            reserve.title.clone().unwrap_or_default(),
+        ]);
+    }
+
+    text
+}
+
+fn csv_line(fields: impl IntoIterator<Item = String>) -> String {
+    let fields: Vec<_> = fields.into_iter().map(|field| csv_escape(&field)).collect();
+    fields.join(",") + "\r\n"
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn format_datetime(datetime: Option<NaiveDateTime>) -> String {
+    datetime
+        .map(|datetime| datetime.format("%Y-%m-%dT%H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::reserves_to_csv;
+    use crate::models::Reserve;
+
+    #[test]
+    fn test_reserves_to_csv() {
+        let reserves = vec![
+            Reserve {
+                id: 1,
+                isbn: "9784001141276".to_string(),
+                library_name: "富山県立大学附属図書館射水館".to_string(),
+                state: "Staging".to_string(),
+                title: Some("ないた赤おに".to_string()),
+                ..Default::default()
+            },
+            Reserve {
+                id: 2,
+                isbn: "9784798121963".to_string(),
+                library_name: "図書館, \"分館\"".to_string(),
+                state: "Reserved".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        let text = reserves_to_csv(&reserves);
+
+        let mut reader = csv::Reader::from_reader(text.as_bytes());
+        let records: Vec<_> = reader.records().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(&records[0][2], "富山県立大学附属図書館射水館");
+        assert_eq!(&records[1][2], "図書館, \"分館\"");
+        assert_eq!(&records[1][5], "");
+        assert_eq!(&records[0][8], "ないた赤おに");
+        assert_eq!(&records[1][8], "");
+    }
+}
@@ -1,73 +1,158 @@
-use crate::models;
+use crate::{
+    error::Error,
+    issued, models,
+    upstream::{Agent, Breaker, BreakerStatus, Limiter, Retry},
+};
 use actix_web::web::Buf;
-use anyhow::Context;
-use awc::Client;
+use awc::ClientRequest;
 use serde_json::Value;
-use std::error::Error;
 
-type E = Box<dyn Error>;
+type E = Error;
 
-#[derive(Debug, Default, Clone)]
+const BASE_URL: &str = "https://www.googleapis.com";
+
+#[derive(Debug, Clone)]
 pub struct GoogleAppState {
     appkey: String,
+    base_url: String,
+    limiter: Limiter,
+    retry: Retry,
+    breaker: Breaker,
+    agent: Agent,
+}
+
+impl Default for GoogleAppState {
+    fn default() -> Self {
+        Self {
+            appkey: String::new(),
+            base_url: BASE_URL.to_string(),
+            limiter: Default::default(),
+            retry: Default::default(),
+            breaker: Default::default(),
+            agent: Default::default(),
+        }
+    }
 }
 
 impl GoogleAppState {
     pub fn new(appkey: &str) -> Self {
         Self {
             appkey: appkey.to_string(),
+            ..Self::default()
         }
     }
 
+    pub fn with_limiter(self, limiter: Limiter) -> Self {
+        Self { limiter, ..self }
+    }
+
+    pub fn with_retry(self, retry: Retry) -> Self {
+        Self { retry, ..self }
+    }
+
+    pub fn with_breaker(self, breaker: Breaker) -> Self {
+        Self { breaker, ..self }
+    }
+
+    pub fn with_agent(self, agent: Agent) -> Self {
+        Self { agent, ..self }
+    }
+
+    pub fn breaker_status(&self) -> BreakerStatus {
+        self.breaker.status()
+    }
+
+    // point to another host, e.g. local mock server
+    pub fn with_base_url(self, base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            ..self
+        }
+    }
+
+    // lang restrict limits results to books in the language, e.g. ja
     pub async fn book_query(
         &self,
         any: &str,
+        lang_restrict: Option<&str>,
         page_size: u32,
         page: u32,
     ) -> Result<models::BookChunk, E> {
-        let start_record = (page_size * page).to_string();
-        let max_record = page_size.to_string();
+        let request = self.book_query_request(any, lang_restrict, page_size, page)?;
 
-        let reader = Client::default()
-            .get("https://www.googleapis.com/books/v1/volumes")
-            .query(&[
-                ("key", self.appkey.as_str()),
-                ("q", any),
-                ("startIndex", start_record.as_str()),
-                ("maxResults", max_record.as_str()),
-            ])?
-            .send()
+        let _permit = self.limiter.acquire().await?;
+
+        let reader = self
+            .breaker
+            .send(&self.retry, request)
             .await?
             .body()
             .await?
             .reader();
 
         let root = serde_json::from_reader(reader)?;
-        let result = parse_book(root).context("failed to parse")?;
+        let mut result = parse_book(root).ok_or(Error::Parse("no book list".to_string()))?;
+        result.page_info = models::PageInfo::new(page, page_size, result.total_count);
 
         Ok(result)
     }
 
+    // request of book query, also to explain what is sent
+    pub fn book_query_request(
+        &self,
+        any: &str,
+        lang_restrict: Option<&str>,
+        page_size: u32,
+        page: u32,
+    ) -> Result<ClientRequest, E> {
+        let start_record = (page_size * page).to_string();
+        let max_record = page_size.to_string();
+
+        let mut query = vec![
+            ("key", self.appkey.as_str()),
+            ("q", any),
+            ("startIndex", start_record.as_str()),
+            ("maxResults", max_record.as_str()),
+        ];
+        if let Some(lang_restrict) = lang_restrict {
+            query.push(("langRestrict", lang_restrict));
+        }
+
+        let request = self
+            .agent
+            .client()
+            .get(format!("{}/books/v1/volumes", self.base_url))
+            .query(&query)?;
+
+        Ok(request)
+    }
+
     pub async fn book_get(&self, isbn: &str) -> Result<models::Book, E> {
         let any = format!("isbn:{isbn}");
 
-        let reader = Client::default()
-            .get("https://www.googleapis.com/books/v1/volumes")
+        let _permit = self.limiter.acquire().await?;
+
+        let request = self
+            .agent
+            .client()
+            .get(format!("{}/books/v1/volumes", self.base_url))
             .query(&[
                 ("key", self.appkey.as_str()),
                 ("q", any.as_str()),
                 ("maxResults", "1"),
-            ])?
-            .send()
+            ])?;
+        let reader = self
+            .breaker
+            .send(&self.retry, request)
             .await?
             .body()
             .await?
             .reader();
 
         let root = serde_json::from_reader(reader)?;
-        let mut result = parse_book(root).context("failed to parse")?;
+        let mut result = parse_book(root).ok_or(Error::Parse("no book list".to_string()))?;
 
-        let item = result.items.pop().context("not found")?;
+        let item = result.items.pop().ok_or(Error::NotFound)?;
 
         Ok(item)
     }
@@ -103,7 +188,9 @@ fn parse_book(node: Value) -> Option<models::BookChunk> {
             let issued_at = node
                 .get("publishedDate")
                 .and_then(|node| node.as_str())
-                .map(|text| text.to_string());
+                .map(|text| issued::normalize(text).unwrap_or_else(|| text.to_string()));
+
+            let year = issued_at.as_deref().and_then(issued::year);
 
             let keywords = vec![];
 
@@ -145,30 +232,110 @@ fn parse_book(node: Value) -> Option<models::BookChunk> {
                 creators,
                 publishers,
                 issued_at,
+                year,
                 isbn,
                 language,
                 annotations,
                 image_url,
+                ..Default::default()
             })
         })
         .collect();
 
     let total_count = node.get("totalItems")?.as_i64()? as u32;
 
-    Some(models::BookChunk { items, total_count })
+    Some(models::BookChunk {
+        items,
+        total_count,
+        ..Default::default()
+    })
 }
 
 #[cfg(test)]
 mod test {
-    use super::GoogleAppState;
+    use super::{parse_book, GoogleAppState};
+    use actix_web::{web, App, HttpRequest, HttpResponse};
     use std::env;
 
+    const FIXTURE: &str = r#"{
+        "kind": "books#volumes",
+        "totalItems": 1,
+        "items": [
+            {
+                "kind": "books#volume",
+                "volumeInfo": {
+                    "title": "エリック・エヴァンスのドメイン駆動設計",
+                    "authors": ["エリック・エヴァンス"],
+                    "publishedDate": "2011-04-09",
+                    "description": "ソフトウェア開発の手法を解説。",
+                    "industryIdentifiers": [
+                        { "type": "ISBN_10", "identifier": "4798121967" },
+                        { "type": "ISBN_13", "identifier": "9784798121963" }
+                    ],
+                    "imageLinks": {
+                        "smallThumbnail": "http://books.google.com/books/content?id=x&zoom=5"
+                    },
+                    "language": "ja"
+                }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse_book() {
+        let root = serde_json::from_str(FIXTURE).unwrap();
+        let chunk = parse_book(root).unwrap();
+        assert_eq!(chunk.total_count, 1);
+
+        let item = &chunk.items[0];
+        assert_eq!(item.title, "エリック・エヴァンスのドメイン駆動設計");
+        assert_eq!(item.creators, vec!["エリック・エヴァンス"]);
+        assert_eq!(item.descriptions, vec!["ソフトウェア開発の手法を解説。"]);
+        assert_eq!(item.issued_at.as_deref(), Some("2011-04-09"));
+        assert_eq!(item.isbn.as_deref(), Some("9784798121963"));
+        assert_eq!(item.language.as_deref(), Some("ja"));
+        assert!(item.image_url.is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_google_lang_restrict() {
+        let srv = actix_test::start(|| {
+            App::new().route(
+                "/books/v1/volumes",
+                web::get().to(|req: HttpRequest| async move {
+                    // echo query string back as title
+                    let title = req.query_string().to_string();
+                    HttpResponse::Ok()
+                        .content_type("application/json")
+                        .body(FIXTURE.replace("エリック・エヴァンスのドメイン駆動設計", &title))
+                }),
+            )
+        });
+        let app = GoogleAppState::new("appkey").with_base_url(&format!("http://{}", srv.addr()));
+
+        let res = app
+            .book_query("ドメイン駆動設計", Some("ja"), 20, 0)
+            .await
+            .unwrap();
+        assert!(res.items[0].title.contains("langRestrict=ja"));
+
+        // unrestricted by default
+        let res = app
+            .book_query("ドメイン駆動設計", None, 20, 0)
+            .await
+            .unwrap();
+        assert!(!res.items[0].title.contains("langRestrict"));
+    }
+
     #[actix_web::test]
     async fn test_google() {
         let appkey = env::var("GOOGLE_APPKEY").unwrap();
         let app = GoogleAppState::new(&appkey);
 
-        let res = app.book_query("ドメイン駆動設計", 20, 0).await.unwrap();
+        let res = app
+            .book_query("ドメイン駆動設計", None, 20, 0)
+            .await
+            .unwrap();
         println!("book query: \"{res:?}\"");
         println!("book query count: \"{:?}\"", res.items.len());
 
@@ -0,0 +1,137 @@
+use crate::{
+    calil_api::CalilAppState, cinii_api::CiniiAppState, error::Error, library_name, models,
+};
+
+type E = Error;
+
+// cinii holders are paginated locally, take all of them at once
+const CINII_LIMIT: u32 = 10000;
+
+// answer who has the book from public (calil) and university (cinii) libraries
+#[derive(Debug, Default, Clone)]
+pub struct HolderAppState {
+    calil: CalilAppState,
+    // none when cinii api key is missing, only public libraries are answered
+    cinii: Option<CiniiAppState>,
+}
+
+impl HolderAppState {
+    pub fn new(calil: CalilAppState) -> Self {
+        Self { calil, cinii: None }
+    }
+
+    pub fn with_cinii(self, cinii: CiniiAppState) -> Self {
+        Self {
+            cinii: Some(cinii),
+            ..self
+        }
+    }
+
+    // query both concurrently, fail only when both of them failed
+    pub async fn holder_query(
+        &self,
+        isbn: &str,
+        library_names: &[&str],
+    ) -> Result<models::HolderChunk, E> {
+        let Some(cinii) = &self.cinii else {
+            let calil = self.calil.holder_query(isbn, library_names).await?;
+            return Ok(holder_merge(calil, Default::default()));
+        };
+
+        let (calil, cinii) = futures::join!(
+            self.calil.holder_query(isbn, library_names),
+            cinii.holder_query(isbn, CINII_LIMIT, 0),
+        );
+
+        let (calil, cinii) = match (calil, cinii) {
+            (Err(err), Err(_)) => return Err(err),
+            (calil, cinii) => (calil.unwrap_or_default(), cinii.unwrap_or_default()),
+        };
+
+        Ok(holder_merge(calil, cinii))
+    }
+}
+
+// dedup by normalized library name, calil loan state wins unless it is unknown
+fn holder_merge(calil: models::HolderChunk, cinii: models::HolderChunk) -> models::HolderChunk {
+    let mut items: Vec<models::Holder> = vec![];
+
+    for item in calil.items.into_iter().chain(cinii.items) {
+        let key = library_name::normalize(&item.library_name);
+
+        match items
+            .iter_mut()
+            .find(|other| library_name::normalize(&other.library_name) == key)
+        {
+            Some(other) if matches!(other.state, models::HolderState::Unknown) => *other = item,
+            Some(_) => {}
+            None => items.push(item),
+        }
+    }
+
+    let total_count = items.len() as u32;
+
+    models::HolderChunk {
+        items,
+        total_count,
+        page_info: models::PageInfo::new(0, total_count, total_count),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::holder_merge;
+    use crate::models::{Holder, HolderChunk, HolderSource, HolderState};
+
+    fn holder(library_name: &str, state: HolderState, source: HolderSource) -> Holder {
+        Holder {
+            isbn: "9784001141276".to_string(),
+            library_name: library_name.to_string(),
+            state,
+            source: Some(source),
+            ..Default::default()
+        }
+    }
+
+    fn chunk(items: Vec<Holder>) -> HolderChunk {
+        HolderChunk {
+            items,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_holder_merge() {
+        use HolderSource::{Calil, Cinii};
+        use HolderState::{Borrowed, Exists, Reservable, Unknown};
+
+        let calil = chunk(vec![
+            holder("東京大学総合図書館", Borrowed, Calil),
+            holder("富山大学附属図書館", Unknown, Calil),
+            holder("富山市立図書館", Reservable, Calil),
+        ]);
+        let cinii = chunk(vec![
+            holder("東京大学 総合図書館", Exists, Cinii),
+            holder("富山大学　附属図書館", Exists, Cinii),
+            holder("京都大学附属図書館", Exists, Cinii),
+        ]);
+
+        let res = holder_merge(calil, cinii);
+        assert_eq!(res.total_count, 4);
+
+        let items: Vec<_> = res
+            .items
+            .into_iter()
+            .map(|item| (item.state, item.source.unwrap()))
+            .collect();
+        assert_eq!(
+            items,
+            vec![
+                (Borrowed, Calil),
+                (Exists, Cinii),
+                (Reservable, Calil),
+                (Exists, Cinii)
+            ]
+        );
+    }
+}
@@ -0,0 +1,96 @@
+// normalize isbn-10 or isbn-13 (hyphenated, full width allowed) into isbn-13
+// return none when checksum is invalid
+pub fn normalize(text: &str) -> Option<String> {
+    let chars: Vec<_> = text
+        .chars()
+        .filter(|c| !matches!(c, '-' | 'ー' | '－' | ' ' | '　'))
+        .map(|c| match c {
+            '０'..='９' | 'Ｘ' | 'ｘ' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        })
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+
+    match chars.len() {
+        10 => {
+            let digits: Vec<_> = chars[..9]
+                .iter()
+                .map(|c| c.to_digit(10))
+                .collect::<Option<_>>()?;
+
+            let check = match chars[9] {
+                'X' => 10,
+                c => c.to_digit(10)?,
+            };
+
+            let sum: u32 = digits
+                .iter()
+                .chain(std::iter::once(&check))
+                .enumerate()
+                .map(|(i, d)| (10 - i as u32) * d)
+                .sum();
+
+            if !sum.is_multiple_of(11) {
+                return None;
+            }
+
+            let mut digits: Vec<_> = [9, 7, 8].into_iter().chain(digits).collect();
+            digits.push(check_digit_13(&digits));
+
+            Some(digits.iter().map(|d| d.to_string()).collect())
+        }
+        13 => {
+            let digits: Vec<_> = chars
+                .iter()
+                .map(|c| c.to_digit(10))
+                .collect::<Option<_>>()?;
+
+            if !chars.starts_with(&['9', '7', '8']) && !chars.starts_with(&['9', '7', '9']) {
+                return None;
+            }
+
+            if check_digit_13(&digits[..12]) != digits[12] {
+                return None;
+            }
+
+            Some(chars.iter().collect())
+        }
+        _ => None,
+    }
+}
+
+fn check_digit_13(digits: &[u32]) -> u32 {
+    let sum: u32 = digits
+        .iter()
+        .enumerate()
+        .map(|(i, d)| if i % 2 == 0 { *d } else { d * 3 })
+        .sum();
+
+    (10 - sum % 10) % 10
+}
+
+#[cfg(test)]
+mod test {
+    use super::normalize;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("9784798121963").as_deref(), Some("9784798121963"));
+        assert_eq!(
+            normalize("978-4-7981-2196-3").as_deref(),
+            Some("9784798121963")
+        );
+        assert_eq!(normalize("4798121967").as_deref(), Some("9784798121963"));
+        assert_eq!(normalize("4-00-114127-2").as_deref(), Some("9784001141276"));
+        assert_eq!(
+            normalize("９７８４７９８１２１９６３").as_deref(),
+            Some("9784798121963")
+        );
+        assert_eq!(normalize("080442957X").as_deref(), Some("9780804429573"));
+
+        assert_eq!(normalize("9784798121964"), None);
+        assert_eq!(normalize("4798121968"), None);
+        assert_eq!(normalize("ドメイン駆動設計"), None);
+        assert_eq!(normalize(""), None);
+    }
+}
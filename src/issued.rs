@@ -0,0 +1,80 @@
+// normalize publication date of any backend into yyyy, yyyy-mm or yyyy-mm-dd
+// e.g. "2013", "2013-04", "2013年04月", "2013-04-15", "201304" and "2013.4"
+// none when no year is found, trailing text like "下旬" is ignored
+pub fn normalize(text: &str) -> Option<String> {
+    let text: String = text
+        .chars()
+        .map(|c| match c {
+            '０'..='９' => char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+            _ => c,
+        })
+        .collect();
+
+    // compact form of openbd, yyyymm or yyyymmdd
+    let groups: Vec<&str> = match text.trim() {
+        compact if compact.chars().all(|c| c.is_ascii_digit()) => match compact.len() {
+            4 => vec![&compact[..4]],
+            6 => vec![&compact[..4], &compact[4..6]],
+            8 => vec![&compact[..4], &compact[4..6], &compact[6..8]],
+            _ => return None,
+        },
+        text => text
+            .split(|c: char| !c.is_ascii_digit())
+            .filter(|group| !group.is_empty())
+            .take(3)
+            .collect(),
+    };
+
+    let year = groups.first().filter(|group| group.len() == 4)?;
+    let year: u32 = year.parse().ok()?;
+
+    let month = groups
+        .get(1)
+        .and_then(|group| group.parse::<u32>().ok())
+        .filter(|month| (1..=12).contains(month));
+    let day = groups
+        .get(2)
+        .and_then(|group| group.parse::<u32>().ok())
+        .filter(|day| (1..=31).contains(day));
+
+    match (month, day) {
+        (Some(month), Some(day)) => Some(format!("{year:04}-{month:02}-{day:02}")),
+        (Some(month), None) => Some(format!("{year:04}-{month:02}")),
+        _ => Some(format!("{year:04}")),
+    }
+}
+
+// year of normalized date
+pub fn year(text: &str) -> Option<u32> {
+    text.get(..4)?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{normalize, year};
+
+    #[test]
+    fn test_normalize() {
+        // ndl
+        assert_eq!(normalize("2013-04").as_deref(), Some("2013-04"));
+        assert_eq!(normalize("2013").as_deref(), Some("2013"));
+        assert_eq!(normalize("2013.4").as_deref(), Some("2013-04"));
+        // rakuten
+        assert_eq!(normalize("2013年04月").as_deref(), Some("2013-04"));
+        assert_eq!(normalize("2013年04月15日").as_deref(), Some("2013-04-15"));
+        assert_eq!(normalize("2013年04月下旬").as_deref(), Some("2013-04"));
+        // google
+        assert_eq!(normalize("2013-04-15").as_deref(), Some("2013-04-15"));
+        // openbd
+        assert_eq!(normalize("201304").as_deref(), Some("2013-04"));
+        assert_eq!(normalize("20130415").as_deref(), Some("2013-04-15"));
+
+        assert_eq!(normalize("１９９８年"), Some("1998".to_string()));
+        assert_eq!(normalize("2013-13").as_deref(), Some("2013"));
+        assert_eq!(normalize("不明"), None);
+        assert_eq!(normalize("13-04"), None);
+
+        assert_eq!(year("2013-04-15"), Some(2013));
+        assert_eq!(year("不明"), None);
+    }
+}
@@ -0,0 +1,23 @@
+use unicode_normalization::UnicodeNormalization;
+
+// normalize library name to compare across backends
+// width is unified by nfkc and every whitespace including u+3000 is removed
+pub fn normalize(name: &str) -> String {
+    name.nfkc().filter(|c| !c.is_whitespace()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::normalize;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("東京大学 総合図書館"), "東京大学総合図書館");
+        assert_eq!(
+            normalize("東京大学　総合図書館"),
+            normalize("東京大学 総合図書館")
+        );
+        assert_eq!(normalize("富山大学\t附属図書館\n"), "富山大学附属図書館");
+        assert_eq!(normalize("ＫＩＴ　図書館２号館"), "KIT図書館2号館");
+    }
+}
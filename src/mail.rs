@@ -0,0 +1,54 @@
+use crate::error::Error;
+use futures::future::{self, BoxFuture};
+
+type E = Error;
+
+// message to a user, e.g. password reset token
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// delivery of mail to users, implemented by transport of the deployment
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), E>>;
+}
+
+// print mail to log instead of sending it, for development
+#[derive(Debug, Default, Clone)]
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send<'a>(&'a self, mail: &'a Mail) -> BoxFuture<'a, Result<(), E>> {
+        eprintln!("info: mail to {}: {}: {}", mail.to, mail.subject, mail.body);
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+// no transport is configured, every mail fails
+#[derive(Debug, Default, Clone)]
+pub struct NoMailer;
+
+impl Mailer for NoMailer {
+    fn send<'a>(&'a self, _: &'a Mail) -> BoxFuture<'a, Result<(), E>> {
+        Box::pin(future::ready(Err(Error::Config(
+            "no mailer is configured".to_string(),
+        ))))
+    }
+}
+
+// token must reach the user only by mail, never in the response
+// failure is logged, caller answers the same either way
+pub async fn token_send(mailer: &dyn Mailer, to: &str, subject: &str, token: &str) {
+    let mail = Mail {
+        to: to.to_string(),
+        subject: subject.to_string(),
+        body: format!("token: {token}"),
+    };
+
+    if let Err(err) = mailer.send(&mail).await {
+        eprintln!("warn: mail: {err}");
+    }
+}
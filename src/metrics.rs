@@ -0,0 +1,77 @@
+use crate::error::Error;
+use actix_web_prom::{PrometheusMetrics, PrometheusMetricsBuilder};
+use once_cell::sync::Lazy;
+use prometheus::{IntCounterVec, Opts, Registry};
+
+type E = Error;
+
+static UPSTREAM_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new(
+            "upstream_failures_total",
+            "Failed calls to external web api",
+        )
+        .namespace("libres"),
+        &["backend"],
+    )
+    .expect("valid metric")
+});
+
+// request count and latency per endpoint and status, exposed on /metrics
+pub fn build() -> Result<PrometheusMetrics, E> {
+    let registry = Registry::new();
+    registry
+        .register(Box::new(UPSTREAM_FAILURES.clone()))
+        .map_err(|err| Error::Config(err.to_string()))?;
+
+    let prometheus = PrometheusMetricsBuilder::new("libres")
+        .registry(registry)
+        .endpoint("/metrics")
+        .build()
+        .map_err(|err| Error::Config(err.to_string()))?;
+
+    Ok(prometheus)
+}
+
+pub fn upstream_failure(backend: &str) {
+    UPSTREAM_FAILURES.with_label_values(&[backend]).inc();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{build, upstream_failure};
+    use actix_web::{
+        body::to_bytes,
+        test::{call_service, init_service, TestRequest},
+        web::get,
+        App, HttpResponse,
+    };
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        let app = init_service(
+            App::new()
+                .wrap(build().unwrap())
+                .route("/", get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        call_service(&app, TestRequest::get().uri("/").to_request()).await;
+        upstream_failure("ndl");
+
+        let res = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert!(res.status().is_success());
+
+        let body = to_bytes(res.into_body()).await.unwrap();
+        let text = std::str::from_utf8(&body).unwrap();
+
+        // every sample line is "name{labels} value"
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            value.parse::<f64>().unwrap();
+        }
+
+        assert!(text.contains("libres_http_requests_total"));
+        assert!(text.contains("libres_upstream_failures_total{backend=\"ndl\"}"));
+    }
+}
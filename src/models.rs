@@ -1,22 +1,104 @@
-use chrono::NaiveDateTime;
-use serde::{Deserialize, Serialize};
+use crate::{error::Error, isbn, upstream::UpstreamRequest};
+use chrono::{DateTime, NaiveDateTime};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{collections::BTreeMap, str::FromStr};
+use unicode_normalization::UnicodeNormalization;
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct User {
     pub id: i64,
     pub email: String,
     pub password: String,
     pub fullname: String,
     pub address: String,
+    pub email_verified: bool,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageInfo {
+    pub page: u32,
+    pub page_size: u32,
+    pub total_count: u32,
+    pub total_pages: u32,
+    pub has_next: bool,
+}
+
+impl PageInfo {
+    pub fn new(page: u32, page_size: u32, total_count: u32) -> Self {
+        let total_pages = match page_size {
+            0 => 0,
+            _ => total_count.div_ceil(page_size),
+        };
+        let has_next = (page as u64 + 1) < total_pages as u64;
+
+        Self {
+            page,
+            page_size,
+            total_count,
+            total_pages,
+            has_next,
+        }
+    }
+
+    // cursor page has no page number, next page exists when a cursor is given back
+    pub fn with_has_next(self, has_next: bool) -> Self {
+        Self { has_next, ..self }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ReserveChunk {
     pub items: Vec<Reserve>,
     pub total_count: u32,
+    pub page_info: PageInfo,
+    pub next_cursor: Option<ReserveCursor>,
+    // given back as modified since of the next sync
+    pub server_time: NaiveDateTime,
+}
+
+// position after a reserve in newest first order, given as "micros_id"
+// stable while reserves are inserted, unlike page offset
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ReserveCursor {
+    pub staging_at: NaiveDateTime,
+    pub id: i64,
+}
+
+impl From<&Reserve> for ReserveCursor {
+    fn from(val: &Reserve) -> Self {
+        Self {
+            staging_at: val.staging_at,
+            id: val.id,
+        }
+    }
+}
+
+impl From<ReserveCursor> for String {
+    fn from(val: ReserveCursor) -> Self {
+        format!("{}_{}", val.staging_at.and_utc().timestamp_micros(), val.id)
+    }
+}
+
+impl TryFrom<String> for ReserveCursor {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let cursor = text.split_once('_').and_then(|(micros, id)| {
+            Some(Self {
+                staging_at: DateTime::from_timestamp_micros(micros.parse().ok()?)?.naive_utc(),
+                id: id.parse().ok()?,
+            })
+        });
+        cursor.ok_or_else(|| format!("invalid cursor: {text:?}"))
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Reserve {
     pub id: i64,
     pub user_id: i64,
@@ -26,68 +108,547 @@ pub struct Reserve {
     pub staging_at: NaiveDateTime,
     pub staged_at: Option<NaiveDateTime>,
     pub reserved_at: Option<NaiveDateTime>,
+    // reserved copy came back to the library, set only by auto advance
+    pub ready_at: Option<NaiveDateTime>,
     pub completed_at: Option<NaiveDateTime>,
+    pub title: Option<String>,
+    // order among active reserves of the same book at the same library, from 1
+    // none when completed
+    pub queue_position: Option<i32>,
+    // last change by any mutation, including queue move
+    pub updated_at: NaiveDateTime,
+}
+
+// stored reserve with holder state checked on request
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReserveAvailability {
+    pub reserve: Reserve,
+    pub holder_state: HolderState,
+    // false when the library has left the index, holder state is unknown then
+    pub library_indexed: bool,
 }
 
+// holder state of one reserve, listed for every active reserve of the user
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReserveHolderState {
+    pub id: i64,
+    pub state: HolderState,
+}
+
+// would-be reserve of dry run, checks have passed but nothing is stored
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReserveDryRun {
+    pub isbn: String,
+    pub library_name: String,
+    pub title: Option<String>,
+    pub holder_state: HolderState,
+}
+
+// holder state of reserved book at the time of check
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HolderSnapshot {
+    pub state: String,
+    pub checked_at: NaiveDateTime,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ReserveFilter {
+    pub state: Option<String>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    // reserves changed at or after, usually server time of the previous response
+    // cancelled reserves are reported only here, in state Cancelled
+    pub modified_since: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Session {
     pub id: i64,
     pub token: String,
     pub user_id: i64,
 }
 
+// session listed to operators, token is cut to its prefix
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    pub id: i64,
+    pub token_prefix: String,
+}
+
+// result with the upstream requests which produced it, for troubleshooting
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Explained<T> {
+    pub result: T,
+    pub upstream: Vec<UpstreamRequest>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct BookChunk {
     pub items: Vec<Book>,
     pub total_count: u32,
+    pub page_info: PageInfo,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Book {
     pub title: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub descriptions: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub keywords: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub creators: Vec<String>,
+    // creators with role stripped from their name, same order as creators
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub creator_roles: Vec<Creator>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub publishers: Vec<String>,
+    // normalized to yyyy, yyyy-mm or yyyy-mm-dd when possible
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub issued_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub year: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub isbn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub annotations: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub image_url: Option<String>,
+    // volume of multi-volume work, already appended to title
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<String>,
+    #[serde(default, alias = "series", skip_serializing_if = "Option::is_none")]
+    pub series_title: Option<String>,
+    // only in full ndl record
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edition: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price: Option<String>,
+    // isbns of other editions collapsed into this book
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub other_isbns: Vec<String>,
+    // backend which found the book by isbn
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+// creator name and role such as 著 or 訳, none when no role is recognized
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Creator {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+}
+
+impl Book {
+    // identity of the same record across backends and caches, isbn-13 when
+    // known, otherwise hash of folded title and primary creator
+    pub fn dedup_key(&self) -> String {
+        if let Some(isbn) = self.isbn.as_deref().and_then(isbn::normalize) {
+            return format!("isbn:{isbn}");
+        }
+
+        let creator = self
+            .creators
+            .first()
+            .map(String::as_str)
+            .unwrap_or_default();
+        let text = format!("{}\0{}", fold(&self.title), fold(creator));
+        format!("title:{:016x}", fnv1a(&text))
+    }
+
+    // combine two records of the same book, fields of self win when populated
+    pub fn merge(self, other: Self) -> Self {
+        fn or_vec(a: Vec<String>, b: Vec<String>) -> Vec<String> {
+            match a.is_empty() {
+                true => b,
+                false => a,
+            }
+        }
+
+        // roles belong to the creators they were taken from
+        let (creators, creator_roles) = match self.creators.is_empty() {
+            true => (other.creators, other.creator_roles),
+            false => (self.creators, self.creator_roles),
+        };
+
+        let isbns: Vec<_> = other
+            .other_isbns
+            .into_iter()
+            .chain(other.isbn.clone())
+            .collect();
+
+        let mut book = Self {
+            title: match self.title.is_empty() {
+                true => other.title,
+                false => self.title,
+            },
+            descriptions: or_vec(self.descriptions, other.descriptions),
+            keywords: or_vec(self.keywords, other.keywords),
+            creators,
+            creator_roles,
+            publishers: or_vec(self.publishers, other.publishers),
+            issued_at: self.issued_at.or(other.issued_at),
+            year: self.year.or(other.year),
+            isbn: self.isbn.or(other.isbn),
+            language: self.language.or(other.language),
+            annotations: or_vec(self.annotations, other.annotations),
+            image_url: self.image_url.or(other.image_url),
+            volume: self.volume.or(other.volume),
+            series_title: self.series_title.or(other.series_title),
+            edition: self.edition.or(other.edition),
+            extent: self.extent.or(other.extent),
+            price: self.price.or(other.price),
+            other_isbns: self.other_isbns,
+            source: self.source.or(other.source),
+        };
+        book.add_other_isbns(isbns);
+        book
+    }
+
+    // keep isbns of other editions as isbn-13, so that any form of own isbn or
+    // an already listed one is not repeated, invalid ones are dropped
+    pub fn add_other_isbns(&mut self, isbns: impl IntoIterator<Item = String>) {
+        let own = self.isbn.as_deref().and_then(isbn::normalize);
+        for other in isbns.into_iter().filter_map(|text| isbn::normalize(&text)) {
+            if own.as_ref() != Some(&other) && !self.other_isbns.contains(&other) {
+                self.other_isbns.push(other);
+            }
+        }
+    }
+}
+
+// width, case and spacing differ between records of the same work
+pub fn fold(text: &str) -> String {
+    text.nfkc()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+// fnv-1a, stable across builds and processes unlike std hasher
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarkChunk {
+    pub items: Vec<Bookmark>,
+    pub total_count: u32,
+    pub page_info: PageInfo,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bookmark {
+    pub isbn: String,
+    pub created_at: NaiveDateTime,
+    pub book: Option<Book>,
+}
+
+// field scoped book search, each given field is combined with and
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct BookFields {
+    pub title: Option<String>,
+    pub creator: Option<String>,
+    pub publisher: Option<String>,
+    pub subject: Option<String>,
+}
+
+impl BookFields {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none()
+            && self.creator.is_none()
+            && self.publisher.is_none()
+            && self.subject.is_none()
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Favorites {
+    pub library_names: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct LibraryChunk {
     pub items: Vec<Library>,
     pub total_count: u32,
+    pub page_info: PageInfo,
+    // sort key of the last library when more follow, see library_query_after
+    pub next_cursor: Option<String>,
+}
+
+impl LibraryChunk {
+    // total count is the number of matched libraries before pagination,
+    // not the length of the page
+    pub fn new(items: Vec<Library>, total_count: u32, page: u32, page_size: u32) -> Self {
+        Self {
+            items,
+            total_count,
+            page_info: PageInfo::new(page, page_size, total_count),
+            next_cursor: None,
+        }
+    }
+
+    pub fn with_next_cursor(self, next_cursor: Option<String>) -> Self {
+        Self {
+            page_info: self.page_info.with_has_next(next_cursor.is_some()),
+            next_cursor,
+            ..self
+        }
+    }
+}
+
+// counts over the pulled library index
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryStats {
+    pub total_count: u32,
+    pub prefecture_counts: BTreeMap<String, u32>,
+    // most libraries first
+    pub top_cities: Vec<CityCount>,
+    pub pulled_at: NaiveDateTime,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CityCount {
+    pub prefecture: String,
+    pub city: String,
+    pub count: u32,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryNames {
+    pub library_names: Vec<String>,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Library {
     pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ingroup_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub prefecture: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub city: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub postcode: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tel: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub geocode: Option<(f64, f64)>,
+    // meters from the requested geocode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance: Option<f64>,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct HolderChunk {
     pub items: Vec<Holder>,
     pub total_count: u32,
+    pub page_info: PageInfo,
+}
+
+impl HolderChunk {
+    // attach human-readable state label, unsupported lang leaves them empty
+    pub fn localize(mut self, lang: &str) -> Self {
+        for item in &mut self.items {
+            item.label = item.state.label(lang).map(|text| text.to_string());
+        }
+        self
+    }
+
+    // availability sorts available libraries first, then by name
+    // unsupported sort keeps input order
+    pub fn sort(mut self, sort: &str) -> Self {
+        if sort == "availability" {
+            self.items.sort_by(|a, b| {
+                a.state
+                    .rank()
+                    .cmp(&b.state.rank())
+                    .then_with(|| a.library_name.cmp(&b.library_name))
+            });
+        }
+        self
+    }
+
+    // keep holders in the given state and count only them
+    // paged result still pages libraries, so its position and next page are kept
+    pub fn filter_state(mut self, state: &HolderState) -> Self {
+        let paged = self.page_info.page_size != self.total_count;
+        self.items.retain(|item| item.state == *state);
+        self.total_count = self.items.len() as u32;
+        self.page_info = match paged {
+            true => PageInfo {
+                total_count: self.total_count,
+                ..self.page_info
+            },
+            false => PageInfo::new(0, self.total_count, self.total_count),
+        };
+        self
+    }
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Holder {
     pub isbn: String,
     pub library_name: String,
     pub state: HolderState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub source: Option<HolderSource>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ingroup_id: Option<String>,
+}
+
+// holder state of a book at the library which was asked
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Holding {
+    pub isbn: String,
+    pub state: HolderState,
+}
+
+// map area in degrees, longitude wraps across the antimeridian when min is
+// greater than max
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GeoBounds {
+    pub min_lat: f64,
+    pub min_lng: f64,
+    pub max_lat: f64,
+    pub max_lng: f64,
+}
+
+impl GeoBounds {
+    // geocode is (latitude, longitude)
+    pub fn contains(&self, geocode: (f64, f64)) -> bool {
+        let (lat, lng) = geocode;
+        let in_lng = match self.min_lng <= self.max_lng {
+            true => self.min_lng <= lng && lng <= self.max_lng,
+            false => self.min_lng <= lng || lng <= self.max_lng,
+        };
+
+        self.min_lat <= lat && lat <= self.max_lat && in_lng
+    }
+}
+
+// holder with coordinates of the library, for plotting on map
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapHolder {
+    #[serde(flatten)]
+    pub holder: Holder,
+    pub geocode: (f64, f64),
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MapHolderChunk {
+    pub items: Vec<MapHolder>,
+    pub total_count: u32,
+    pub page_info: PageInfo,
+}
+
+// one step of calil check session for client which polls by itself,
+// polled again with session while has next
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HolderSession {
+    pub session: String,
+    pub has_next: bool,
+    pub items: Vec<Holder>,
+    // systems still checking, their libraries are not in items yet
+    pub pending_system_ids: Vec<String>,
+}
+
+// backend configured in this deployment and what it answers
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Backend {
+    pub name: String,
+    pub label: String,
+    pub book_query: bool,
+    pub book_get: bool,
+    pub holder: bool,
+}
+
+// book and its holders in one payload, either is none when its lookup failed
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Availability {
+    pub book: Option<Book>,
+    pub holders: Option<HolderChunk>,
 }
 
+// search result with holder state at libraries near the user, holders are
+// none when the check failed
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchBook {
+    #[serde(flatten)]
+    pub book: Book,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub holders: Option<Vec<Holder>>,
+}
+
+// libraries which were checked, nearest first with distance
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchChunk {
+    pub items: Vec<SearchBook>,
+    pub libraries: Vec<Library>,
+    pub total_count: u32,
+    pub page_info: PageInfo,
+}
+
+// identifier of cinii books record, for union catalog and opac links
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Ncid {
+    pub isbn: String,
+    pub ncid: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HolderSource {
+    Calil,
+    Cinii,
+}
+
+// deserialized from variant name in any case or japanese label, see from_str
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
 pub enum HolderState {
     #[default]
     Nothing,
@@ -96,4 +657,435 @@ pub enum HolderState {
     Reserved,
     Borrowed,
     Inplace,
+    Unknown,
+}
+
+impl HolderState {
+    pub const ALL: [HolderState; 7] = [
+        HolderState::Nothing,
+        HolderState::Exists,
+        HolderState::Reservable,
+        HolderState::Reserved,
+        HolderState::Borrowed,
+        HolderState::Inplace,
+        HolderState::Unknown,
+    ];
+
+    // lower is more available, unknown may still be held so it precedes nothing
+    pub fn rank(&self) -> u8 {
+        match self {
+            HolderState::Reservable => 0,
+            HolderState::Exists => 1,
+            HolderState::Inplace => 2,
+            HolderState::Reserved => 3,
+            HolderState::Borrowed => 4,
+            HolderState::Unknown => 5,
+            HolderState::Nothing => 6,
+        }
+    }
+
+    // same as serialized name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HolderState::Nothing => "Nothing",
+            HolderState::Exists => "Exists",
+            HolderState::Reservable => "Reservable",
+            HolderState::Reserved => "Reserved",
+            HolderState::Borrowed => "Borrowed",
+            HolderState::Inplace => "Inplace",
+            HolderState::Unknown => "Unknown",
+        }
+    }
+
+    // labels follow the wording of calil
+    pub fn label(&self, lang: &str) -> Option<&'static str> {
+        let label = match (lang, self) {
+            ("ja", HolderState::Nothing) => "蔵書なし",
+            ("ja", HolderState::Exists) => "蔵書あり",
+            ("ja", HolderState::Reservable) => "貸出可",
+            ("ja", HolderState::Reserved) => "予約中",
+            ("ja", HolderState::Borrowed) => "貸出中",
+            ("ja", HolderState::Inplace) => "館内のみ",
+            ("ja", HolderState::Unknown) => "不明",
+            ("en", HolderState::Nothing) => "Not held",
+            ("en", HolderState::Exists) => "Held",
+            ("en", HolderState::Reservable) => "Available",
+            ("en", HolderState::Reserved) => "Reserved",
+            ("en", HolderState::Borrowed) => "On loan",
+            ("en", HolderState::Inplace) => "In-library use only",
+            ("en", HolderState::Unknown) => "Unknown",
+            _ => return None,
+        };
+        Some(label)
+    }
+}
+
+// client input, e.g. state filter of holder query
+impl FromStr for HolderState {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        HolderState::ALL
+            .into_iter()
+            .find(|state| {
+                state.as_str().eq_ignore_ascii_case(text) || state.label("ja") == Some(text)
+            })
+            .ok_or_else(|| Error::Validation(format!("unknown holder state: {text:?}")))
+    }
+}
+
+impl<'de> Deserialize<'de> for HolderState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Book, BookChunk, GeoBounds, Holder, HolderChunk, HolderState, Library, LibraryChunk,
+        PageInfo,
+    };
+
+    #[test]
+    fn test_page_info() {
+        let info = PageInfo::new(0, 20, 40);
+        assert_eq!(info.total_pages, 2);
+        assert!(info.has_next);
+
+        let info = PageInfo::new(1, 20, 40);
+        assert_eq!(info.total_pages, 2);
+        assert!(!info.has_next);
+
+        let info = PageInfo::new(1, 20, 41);
+        assert_eq!(info.total_pages, 3);
+        assert!(info.has_next);
+
+        let info = PageInfo::new(0, 20, 0);
+        assert_eq!(info.total_pages, 0);
+        assert!(!info.has_next);
+
+        let info = PageInfo::new(0, 0, 10);
+        assert_eq!(info.total_pages, 0);
+        assert!(!info.has_next);
+    }
+
+    #[test]
+    fn test_library_chunk_new() {
+        // count of matched libraries, page holds only a part of them
+        let items = vec![Library::default(); 2];
+        let chunk = LibraryChunk::new(items, 5, 1, 2);
+        assert_eq!(chunk.items.len(), 2);
+        assert_eq!(chunk.total_count, 5);
+        assert_eq!(chunk.page_info.total_count, 5);
+        assert_eq!(chunk.page_info.total_pages, 3);
+        assert!(chunk.page_info.has_next);
+
+        let chunk = LibraryChunk::new(vec![], 5, 2, 2);
+        assert_eq!(chunk.total_count, 5);
+        assert!(!chunk.page_info.has_next);
+    }
+
+    #[test]
+    fn test_holder_label() {
+        let cases = [
+            (HolderState::Nothing, "蔵書なし"),
+            (HolderState::Exists, "蔵書あり"),
+            (HolderState::Reservable, "貸出可"),
+            (HolderState::Reserved, "予約中"),
+            (HolderState::Borrowed, "貸出中"),
+            (HolderState::Inplace, "館内のみ"),
+            (HolderState::Unknown, "不明"),
+        ];
+        for (state, label) in cases {
+            assert_eq!(state.label("ja"), Some(label));
+        }
+        assert_eq!(HolderState::Borrowed.label("en"), Some("On loan"));
+        assert_eq!(HolderState::Borrowed.label("fr"), None);
+
+        let chunk = HolderChunk {
+            items: vec![Holder {
+                state: HolderState::Reservable,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let value = serde_json::to_value(chunk.clone()).unwrap();
+        assert!(value["items"][0].get("label").is_none());
+
+        let value = serde_json::to_value(chunk.localize("ja")).unwrap();
+        assert_eq!(value["items"][0]["state"], "Reservable");
+        assert_eq!(value["items"][0]["label"], "貸出可");
+    }
+
+    #[test]
+    fn test_holder_sort() {
+        let holder = |library_name: &str, state: HolderState| Holder {
+            library_name: library_name.to_string(),
+            state,
+            ..Default::default()
+        };
+        let chunk = HolderChunk {
+            items: vec![
+                holder("D図書館", HolderState::Nothing),
+                holder("C図書館", HolderState::Borrowed),
+                holder("B図書館", HolderState::Reservable),
+                holder("E図書館", HolderState::Unknown),
+                holder("A図書館", HolderState::Reservable),
+                holder("F図書館", HolderState::Inplace),
+            ],
+            ..Default::default()
+        };
+        let names = |chunk: &HolderChunk| -> Vec<String> {
+            chunk
+                .items
+                .iter()
+                .map(|item| item.library_name.clone())
+                .collect()
+        };
+
+        let sorted = chunk.clone().sort("availability");
+        assert_eq!(
+            names(&sorted),
+            vec![
+                "A図書館",
+                "B図書館",
+                "F図書館",
+                "C図書館",
+                "E図書館",
+                "D図書館"
+            ]
+        );
+
+        let unsorted = chunk.clone().sort("unknown");
+        assert_eq!(names(&unsorted), names(&chunk));
+    }
+
+    #[test]
+    fn test_camel_case() {
+        let chunk = BookChunk {
+            items: vec![Book {
+                issued_at: Some("2011-04".to_string()),
+                ..Default::default()
+            }],
+            total_count: 1,
+            page_info: PageInfo::new(0, 20, 1),
+        };
+        let value = serde_json::to_value(&chunk).unwrap();
+        assert_eq!(value["totalCount"], 1);
+        assert_eq!(value["pageInfo"]["hasNext"], false);
+        assert_eq!(value["items"][0]["issuedAt"], "2011-04");
+        assert!(value.get("total_count").is_none());
+
+        // deserialized with the same names
+        let chunk: BookChunk = serde_json::from_value(value).unwrap();
+        assert_eq!(chunk.total_count, 1);
+    }
+
+    #[test]
+    fn test_holder_state_from_str() {
+        let cases = [
+            ("Exists", HolderState::Exists),
+            ("exists", HolderState::Exists),
+            ("EXISTS", HolderState::Exists),
+            ("蔵書あり", HolderState::Exists),
+            ("reservable", HolderState::Reservable),
+            ("貸出可", HolderState::Reservable),
+            ("reserved", HolderState::Reserved),
+            ("予約中", HolderState::Reserved),
+            ("borrowed", HolderState::Borrowed),
+            ("貸出中", HolderState::Borrowed),
+            ("inplace", HolderState::Inplace),
+            ("館内のみ", HolderState::Inplace),
+            ("nothing", HolderState::Nothing),
+            ("蔵書なし", HolderState::Nothing),
+            ("unknown", HolderState::Unknown),
+            ("不明", HolderState::Unknown),
+            (" Borrowed ", HolderState::Borrowed),
+        ];
+        for (text, state) in cases {
+            assert_eq!(text.parse::<HolderState>().unwrap(), state, "{text}");
+        }
+
+        let err = "on loan".parse::<HolderState>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid: unknown holder state: "on loan""#
+        );
+        assert!("".parse::<HolderState>().is_err());
+
+        // serialized name is read back, and so is client input
+        let state: HolderState = serde_json::from_str(r#""Reservable""#).unwrap();
+        assert_eq!(state, HolderState::Reservable);
+        let state: HolderState = serde_json::from_str(r#""貸出中""#).unwrap();
+        assert_eq!(state, HolderState::Borrowed);
+        assert!(serde_json::from_str::<HolderState>(r#""lent""#).is_err());
+
+        let chunk = HolderChunk {
+            items: vec![
+                Holder {
+                    state: HolderState::Borrowed,
+                    ..Default::default()
+                },
+                Holder {
+                    state: HolderState::Exists,
+                    ..Default::default()
+                },
+            ],
+            total_count: 2,
+            page_info: PageInfo::new(0, 2, 2),
+        };
+        let chunk = chunk.filter_state(&"exists".parse().unwrap());
+        assert_eq!(chunk.items.len(), 1);
+        assert_eq!(chunk.items[0].state, HolderState::Exists);
+        assert_eq!(chunk.total_count, 1);
+        assert_eq!(chunk.page_info.total_count, 1);
+        assert!(!chunk.page_info.has_next);
+    }
+
+    #[test]
+    fn test_book_dedup_key() {
+        // isbn-10 and hyphenated isbn-13 are the same book
+        let book = |isbn: &str| Book {
+            isbn: Some(isbn.to_string()),
+            title: "ぐりとぐら".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(book("4-8340-0082-6").dedup_key(), "isbn:9784834000825");
+        assert_eq!(
+            book("4-8340-0082-6").dedup_key(),
+            book("978-4-8340-0082-5").dedup_key()
+        );
+
+        // without valid isbn, title and primary creator are folded
+        let book = |title: &str, creators: &[&str]| Book {
+            isbn: Some("invalid".to_string()),
+            title: title.to_string(),
+            creators: creators.iter().map(|text| text.to_string()).collect(),
+            ..Default::default()
+        };
+        let key = book("ぐりとぐら", &["なかがわりえこ", "おおむらゆりこ"]).dedup_key();
+        assert!(key.starts_with("title:"));
+        assert_eq!(key, book("ぐり と ぐら", &["なかがわりえこ"]).dedup_key());
+        assert_ne!(key, book("ぐりとぐら", &["おおむらゆりこ"]).dedup_key());
+        assert_ne!(
+            key,
+            book("ぐりとぐらのえんそく", &["なかがわりえこ"]).dedup_key()
+        );
+        assert_eq!(
+            book("Domain Driven Design", &["Evans"]).dedup_key(),
+            book("ＤＯＭＡＩＮ ｄｒｉｖｅｎ design", &["evans"]).dedup_key()
+        );
+    }
+
+    #[test]
+    fn test_book_merge() {
+        let first = Book {
+            title: "ぐりとぐら".to_string(),
+            isbn: Some("9784834000825".to_string()),
+            creators: vec!["なかがわりえこ".to_string()],
+            issued_at: Some("1967".to_string()),
+            source: Some("ndl".to_string()),
+            ..Default::default()
+        };
+        let second = Book {
+            title: "ぐりとぐら (こどものとも傑作集)".to_string(),
+            isbn: Some("9784834000825".to_string()),
+            creators: vec!["中川李枝子".to_string()],
+            descriptions: vec!["のねずみのふたごのお話".to_string()],
+            issued_at: Some("1967-01".to_string()),
+            image_url: Some("https://example.com/cover.jpg".to_string()),
+            other_isbns: vec!["9784834020144".to_string()],
+            source: Some("google".to_string()),
+            ..Default::default()
+        };
+
+        // populated fields of self win, missing ones are taken from other
+        let book = first.merge(second);
+        assert_eq!(book.title, "ぐりとぐら");
+        assert_eq!(book.creators, vec!["なかがわりえこ"]);
+        assert_eq!(book.issued_at.as_deref(), Some("1967"));
+        assert_eq!(book.source.as_deref(), Some("ndl"));
+        assert_eq!(book.descriptions, vec!["のねずみのふたごのお話"]);
+        assert_eq!(
+            book.image_url.as_deref(),
+            Some("https://example.com/cover.jpg")
+        );
+        assert_eq!(book.other_isbns, vec!["9784834020144"]);
+
+        // empty title is filled as well
+        let book = Book::default().merge(book);
+        assert_eq!(book.title, "ぐりとぐら");
+        assert_eq!(book.isbn.as_deref(), Some("9784834000825"));
+        assert_eq!(book.other_isbns, vec!["9784834020144"]);
+
+        // isbn-10 of the same book is not another edition, others are kept as isbn-13
+        let isbn = |isbn: &str| Book {
+            isbn: Some(isbn.to_string()),
+            ..Default::default()
+        };
+        let book = isbn("4-8340-0082-6").merge(isbn("9784834000825"));
+        assert!(book.other_isbns.is_empty());
+        let book = isbn("9784834000825")
+            .merge(isbn("4-8340-2014-2"))
+            .merge(isbn("9784834020144"));
+        assert_eq!(book.other_isbns, vec!["9784834020144"]);
+    }
+
+    #[test]
+    fn test_geo_bounds() {
+        let toyama = GeoBounds {
+            min_lat: 36.2,
+            min_lng: 136.7,
+            max_lat: 37.0,
+            max_lng: 137.8,
+        };
+        assert!(toyama.contains((36.7077262, 137.0958753)));
+        assert!(toyama.contains((36.2, 137.8)));
+        assert!(!toyama.contains((35.681, 139.767)));
+        assert!(!toyama.contains((36.7, 139.0)));
+
+        // box across the antimeridian
+        let pacific = GeoBounds {
+            min_lat: -20.0,
+            min_lng: 170.0,
+            max_lat: 20.0,
+            max_lng: -170.0,
+        };
+        assert!(pacific.contains((0.0, 175.0)));
+        assert!(pacific.contains((0.0, -175.0)));
+        assert!(!pacific.contains((0.0, 0.0)));
+    }
+
+    #[test]
+    fn test_book_skip_empty() {
+        let book = Book {
+            title: "ぐりとぐら".to_string(),
+            creators: vec!["なかがわりえこ".to_string()],
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&book).unwrap();
+        let keys: Vec<_> = value.as_object().unwrap().keys().cloned().collect();
+        assert_eq!(keys, vec!["creators", "title"]);
+
+        // absent keys are filled with defaults
+        let book: Book = serde_json::from_value(value).unwrap();
+        assert_eq!(book.title, "ぐりとぐら");
+        assert!(book.keywords.is_empty());
+        assert!(book.descriptions.is_empty());
+        assert!(book.isbn.is_none());
+
+        let library = Library {
+            name: "テスト市立図書館".to_string(),
+            geocode: Some((36.7077262, 137.0958753)),
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&library).unwrap();
+        let keys: Vec<_> = value.as_object().unwrap().keys().cloned().collect();
+        assert_eq!(keys, vec!["geocode", "name"]);
+        let library: Library = serde_json::from_value(value).unwrap();
+        assert!(library.system_id.is_none());
+    }
 }
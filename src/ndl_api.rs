@@ -1,43 +1,129 @@
-use crate::models;
+use crate::{
+    error::Error,
+    issued, models,
+    upstream::{self, Agent, Breaker, BreakerStatus, Limiter, Retry},
+};
 use actix_web::web::Buf;
-use anyhow::Context;
-use awc::Client;
+use awc::ClientRequest;
 use roxmltree::Node;
-use std::{error::Error, io::Read};
+use std::io::Read;
 
-type E = Box<dyn Error>;
+type E = Error;
 
-#[derive(Debug, Default, Clone)]
-pub struct NdlAppState;
+const BASE_URL: &str = "https://iss.ndl.go.jp";
+
+// role suffix of creator, combined one like 作・絵 is joined by middle dot
+const CREATOR_ROLES: [&str; 22] = [
+    "著", "編", "訳", "監修", "編著", "著者", "編者", "訳者", "編集", "共著", "共編", "監訳", "作",
+    "絵", "画", "文", "写真", "原作", "原著", "撰", "述", "校注",
+];
+
+#[derive(Debug, Clone)]
+pub struct NdlAppState {
+    base_url: String,
+    record_schema: RecordSchema,
+    creator_roles: bool,
+    limiter: Limiter,
+    retry: Retry,
+    breaker: Breaker,
+    agent: Agent,
+}
+
+impl Default for NdlAppState {
+    fn default() -> Self {
+        Self {
+            base_url: BASE_URL.to_string(),
+            record_schema: Default::default(),
+            creator_roles: false,
+            limiter: Default::default(),
+            retry: Default::default(),
+            breaker: Default::default(),
+            agent: Default::default(),
+        }
+    }
+}
+
+// simple: dcndl_simple, compact dublin core
+// full: dcndl, rdf which also has series, edition, extent and price
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum RecordSchema {
+    #[default]
+    Simple,
+    Full,
+}
+
+impl RecordSchema {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RecordSchema::Simple => "dcndl_simple",
+            RecordSchema::Full => "dcndl",
+        }
+    }
+}
 
 impl NdlAppState {
     pub fn new() -> Self {
-        Self
+        Self::default()
+    }
+
+    pub fn with_record_schema(self, record_schema: RecordSchema) -> Self {
+        Self {
+            record_schema,
+            ..self
+        }
+    }
+
+    // strip role like 著 from creator names, raw names stay unless enabled
+    pub fn with_creator_roles(self, creator_roles: bool) -> Self {
+        Self {
+            creator_roles,
+            ..self
+        }
+    }
+
+    pub fn with_limiter(self, limiter: Limiter) -> Self {
+        Self { limiter, ..self }
+    }
+
+    pub fn with_retry(self, retry: Retry) -> Self {
+        Self { retry, ..self }
+    }
+
+    pub fn with_breaker(self, breaker: Breaker) -> Self {
+        Self { breaker, ..self }
     }
 
-    pub async fn book_query(
+    pub fn with_agent(self, agent: Agent) -> Self {
+        Self { agent, ..self }
+    }
+
+    pub fn breaker_status(&self) -> BreakerStatus {
+        self.breaker.status()
+    }
+
+    // point to another host, e.g. local mock server
+    pub fn with_base_url(self, base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            ..self
+        }
+    }
+
+    // search by title, creator, publisher and subject index in addition to anywhere
+    pub async fn book_query_fields(
         &self,
         any: &str,
+        fields: &models::BookFields,
         page_size: u32,
         page: u32,
     ) -> Result<models::BookChunk, E> {
-        let search_query = format!(
-            "mediatype=1 AND anywhere=\"{any}\" AND sortBy=\"issued_date/sort.descending\"",
-        );
-        let max_records = page_size.to_string();
-        let start_record = (page * page_size + 1).to_string();
+        let request = self.book_query_request(any, fields, page_size, page)?;
 
-        let mut reader = Client::default()
-            .get("https://iss.ndl.go.jp/api/sru")
-            .query(&[
-                ("operation", "searchRetrieve"),
-                ("query", search_query.as_str()),
-                ("maximumRecords", max_records.as_str()),
-                ("startRecord", start_record.as_str()),
-                ("recordPacking", "xml"),
-                ("recordSchema", "dcndl_simple"),
-            ])?
-            .send()
+        let _permit = self.limiter.acquire().await?;
+
+        let mut reader = self
+            .breaker
+            .send(&self.retry, request)
             .await?
             .body()
             .await?
@@ -47,24 +133,63 @@ impl NdlAppState {
         reader.read_to_string(&mut text)?;
         let document = roxmltree::Document::parse(&text)?;
         let root = document.root_element();
-        let chunk = parse_book(root).context("failed to parse")?;
+        let mut chunk = parse_book(root, self.record_schema, self.creator_roles)
+            .ok_or(Error::Parse("no sru response".to_string()))?;
+        chunk.page_info = models::PageInfo::new(page, page_size, chunk.total_count);
 
         Ok(chunk)
     }
 
+    // sru request of book query, also to explain what is sent
+    pub fn book_query_request(
+        &self,
+        any: &str,
+        fields: &models::BookFields,
+        page_size: u32,
+        page: u32,
+    ) -> Result<ClientRequest, E> {
+        let search_query = search_query(any, fields)?;
+        let max_records = page_size.to_string();
+        let start_record = (page * page_size + 1).to_string();
+
+        let request = self
+            .agent
+            .client()
+            .get(format!("{}/api/sru", self.base_url))
+            .query(&[
+                ("operation", "searchRetrieve"),
+                ("query", search_query.as_str()),
+                ("maximumRecords", max_records.as_str()),
+                ("startRecord", start_record.as_str()),
+                ("recordPacking", "xml"),
+                ("recordSchema", self.record_schema.as_str()),
+            ])?;
+
+        Ok(request)
+    }
+
     pub async fn book_get(&self, isbn: &str) -> Result<models::Book, E> {
-        let search_query = format!("isbn=\"{isbn}\" AND sortBy=\"issued_date/sort.descending\"");
+        let search_query = format!(
+            "isbn={} AND sortBy=\"issued_date/sort.descending\"",
+            cql_quote(isbn)?
+        );
 
-        let mut reader = Client::default()
-            .get("https://iss.ndl.go.jp/api/sru")
+        let _permit = self.limiter.acquire().await?;
+
+        let request = self
+            .agent
+            .client()
+            .get(format!("{}/api/sru", self.base_url))
             .query(&[
                 ("operation", "searchRetrieve"),
                 ("query", search_query.as_str()),
                 ("maximumRecords", "1"),
                 ("recordPacking", "xml"),
-                ("recordSchema", "dcndl_simple"),
-            ])?
-            .send()
+                ("recordSchema", self.record_schema.as_str()),
+            ])?;
+        let mut reader = self
+            .breaker
+            .send(&self.retry, request)
             .await?
             .body()
             .await?
@@ -74,106 +199,44 @@ impl NdlAppState {
         reader.read_to_string(&mut text)?;
         let document = roxmltree::Document::parse(&text)?;
         let root = document.root_element();
-        let mut chunk = parse_book(root).context("failed to parse")?;
+        let mut chunk = parse_book(root, self.record_schema, self.creator_roles)
+            .ok_or(Error::Parse("no sru response".to_string()))?;
 
-        let item = chunk.items.pop().context("not found")?;
+        let item = chunk.items.pop().ok_or(Error::NotFound)?;
 
         Ok(item)
     }
 }
 
-fn parse_book(node: Node) -> Option<models::BookChunk> {
-    const NS_XSI: &str = "http://www.w3.org/2001/XMLSchema-instance";
+// ndl answers placeholder image for isbn without cover
+fn thumbnail_url(base_url: &str, isbn: &str) -> String {
+    format!("{base_url}/thumbnail/{isbn}")
+}
 
+// sru omits records element when nothing matched, so only count is required
+fn parse_book(
+    node: Node,
+    record_schema: RecordSchema,
+    creator_roles: bool,
+) -> Option<models::BookChunk> {
     let items = node
         .children()
-        .find(|node| node.has_tag_name("records"))?
-        .children()
+        .filter(|node| node.has_tag_name("records"))
+        .flat_map(|node| node.children())
         .filter(|node| node.has_tag_name("record"))
         .filter_map(|node| {
-            let item = node
-                .children()
-                .find(|node| node.has_tag_name("recordData"))?
-                .children()
-                .find(|node| node.has_tag_name("dc"))?;
-
-            let title = item
-                .children()
-                .find(|node| node.has_tag_name("title"))?
-                .text()?
-                .to_string();
-
-            let descriptions = item
-                .children()
-                .filter(|node| node.has_tag_name("abstract"))
-                .filter_map(|node| node.text())
-                .map(|text| text.to_string())
-                .collect();
-
-            let keywords = item
-                .children()
-                .filter(|node| node.has_tag_name("subject"))
-                .filter_map(|node| node.text())
-                .map(|text| text.to_string())
-                .collect();
-
-            let creators = item
-                .children()
-                .filter(|node| node.has_tag_name("creator"))
-                .filter_map(|node| node.text())
-                .map(|text| text.to_string())
-                .collect();
-
-            let publishers = item
+            let data = node
                 .children()
-                .filter(|node| node.has_tag_name("publisher"))
-                .filter_map(|node| node.text())
-                .map(|text| text.to_string())
-                .collect();
+                .find(|node| node.has_tag_name("recordData"))?;
 
-            let issued_at = item
-                .children()
-                .find(|node| node.has_tag_name("issued"))
-                .and_then(|node| node.text())
-                .map(|text| text.to_string());
+            let book = match record_schema {
+                RecordSchema::Simple => parse_simple(data),
+                RecordSchema::Full => parse_full(data),
+            }?;
 
-            let isbn = item
-                .children()
-                .find(|node| {
-                    node.has_tag_name("identifier")
-                        && node.attribute((NS_XSI, "type")) == Some("dcndl:ISBN")
-                })
-                .and_then(|node| node.text())
-                .map(|text| text.to_string());
-
-            let language = item
-                .children()
-                .find(|node| node.has_tag_name("language"))
-                .and_then(|node| node.text())
-                .map(|text| text.to_string());
-
-            let annotations = item
-                .children()
-                .filter(|node| node.has_tag_name("description"))
-                .filter_map(|node| node.text())
-                .map(|text| text.to_string())
-                .collect();
-
-            let image_url = isbn
-                .as_ref()
-                .map(|text| format!("https://iss.ndl.go.jp/thumbnail/{text}"));
-
-            Some(models::Book {
-                title,
-                descriptions,
-                keywords,
-                creators,
-                publishers,
-                issued_at,
-                isbn,
-                language,
-                annotations,
-                image_url,
+            Some(match creator_roles {
+                true => creator_normalize(book),
+                false => book,
             })
         })
         .collect();
@@ -185,22 +248,728 @@ fn parse_book(node: Node) -> Option<models::BookChunk> {
         .parse()
         .ok()?;
 
-    Some(models::BookChunk { items, total_count })
+    Some(models::BookChunk {
+        items,
+        total_count,
+        ..Default::default()
+    })
+}
+
+fn parse_simple(node: Node) -> Option<models::Book> {
+    const NS_XSI: &str = "http://www.w3.org/2001/XMLSchema-instance";
+
+    let item = node.children().find(|node| node.has_tag_name("dc"))?;
+
+    let title = item
+        .children()
+        .find(|node| node.has_tag_name("title"))?
+        .text()?
+        .to_string();
+
+    let text = |name: &str| {
+        item.children()
+            .find(|node| node.has_tag_name(name))
+            .and_then(|node| node.text())
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+            .map(|text| text.to_string())
+    };
+    let volume = text("volume");
+    let series_title = text("seriesTitle");
+
+    let descriptions = item
+        .children()
+        .filter(|node| node.has_tag_name("abstract"))
+        .filter_map(|node| node.text())
+        .map(|text| text.to_string())
+        .collect();
+
+    let keywords = item
+        .children()
+        .filter(|node| node.has_tag_name("subject"))
+        .filter_map(|node| node.text())
+        .map(|text| text.to_string())
+        .collect();
+
+    let creators = item
+        .children()
+        .filter(|node| node.has_tag_name("creator"))
+        .filter_map(|node| node.text())
+        .map(|text| text.to_string())
+        .collect();
+
+    let publishers = item
+        .children()
+        .filter(|node| node.has_tag_name("publisher"))
+        .filter_map(|node| node.text())
+        .map(|text| text.to_string())
+        .collect();
+
+    let issued_at = item
+        .children()
+        .find(|node| node.has_tag_name("issued"))
+        .and_then(|node| node.text())
+        .map(|text| issued::normalize(text).unwrap_or_else(|| text.to_string()));
+
+    let year = issued_at.as_deref().and_then(issued::year);
+
+    let isbn = item
+        .children()
+        .find(|node| {
+            node.has_tag_name("identifier")
+                && node.attribute((NS_XSI, "type")) == Some("dcndl:ISBN")
+        })
+        .and_then(|node| node.text())
+        .map(|text| text.to_string());
+
+    let language = item
+        .children()
+        .find(|node| node.has_tag_name("language"))
+        .and_then(|node| node.text())
+        .map(|text| text.to_string());
+
+    let annotations = item
+        .children()
+        .filter(|node| node.has_tag_name("description"))
+        .filter_map(|node| node.text())
+        .map(|text| text.to_string())
+        .collect();
+
+    let image_url = isbn.as_ref().map(|text| thumbnail_url(BASE_URL, text));
+
+    Some(models::Book {
+        title: display_title(title, volume.as_deref()),
+        descriptions,
+        keywords,
+        creators,
+        publishers,
+        issued_at,
+        year,
+        isbn,
+        language,
+        annotations,
+        image_url,
+        volume,
+        series_title,
+        ..Default::default()
+    })
+}
+
+// split creators into name and role, e.g. "山田太郎 著" into 山田太郎 and 著
+fn creator_normalize(book: models::Book) -> models::Book {
+    let creator_roles: Vec<_> = book
+        .creators
+        .iter()
+        .map(|text| creator_split(text))
+        .collect();
+
+    models::Book {
+        creators: creator_roles
+            .iter()
+            .map(|creator| creator.name.clone())
+            .collect(),
+        creator_roles,
+        ..book
+    }
+}
+
+// role is the last word, optionally in brackets as in "[著]", made of known
+// roles only, otherwise the creator is kept as is
+fn creator_split(text: &str) -> models::Creator {
+    let text = text.trim();
+    let unknown = || models::Creator {
+        name: text.to_string(),
+        role: None,
+    };
+
+    let Some((name, role)) = text.rsplit_once(char::is_whitespace) else {
+        return unknown();
+    };
+    let (name, role) = (name.trim(), role.trim());
+    let role = role
+        .strip_prefix('[')
+        .and_then(|role| role.strip_suffix(']'))
+        .unwrap_or(role);
+
+    if name.is_empty() || !role.split('・').all(|role| CREATOR_ROLES.contains(&role)) {
+        return unknown();
+    }
+
+    models::Creator {
+        name: name.to_string(),
+        role: Some(role.to_string()),
+    }
+}
+
+// bibliographic resource of rdf, values are either text or nested description
+fn parse_full(node: Node) -> Option<models::Book> {
+    const NS_RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+
+    let item = node
+        .descendants()
+        .find(|node| node.has_tag_name("BibResource"))?;
+
+    let values = |name: &str| -> Vec<String> {
+        item.children()
+            .filter(|node| node.has_tag_name(name))
+            .filter_map(rdf_text)
+            .collect()
+    };
+    let value = |name: &str| values(name).into_iter().next();
+
+    let volume = value("volume");
+    let title = display_title(value("title")?, volume.as_deref());
+
+    let issued_at = value("issued").map(|text| issued::normalize(&text).unwrap_or(text));
+
+    let year = issued_at.as_deref().and_then(issued::year);
+
+    let isbn = item
+        .children()
+        .find(|node| {
+            node.has_tag_name("identifier")
+                && node
+                    .attribute((NS_RDF, "datatype"))
+                    .is_some_and(|datatype| datatype.ends_with("ISBN"))
+        })
+        .and_then(rdf_text)
+        .map(|text| text.replace('-', ""));
+
+    let image_url = isbn.as_ref().map(|text| thumbnail_url(BASE_URL, text));
+
+    Some(models::Book {
+        title,
+        descriptions: values("abstract"),
+        keywords: values("subject"),
+        creators: values("creator"),
+        publishers: values("publisher"),
+        issued_at,
+        year,
+        isbn,
+        language: value("language"),
+        annotations: values("description"),
+        image_url,
+        volume,
+        series_title: value("seriesTitle"),
+        edition: value("edition"),
+        extent: value("extent"),
+        price: value("price"),
+        ..Default::default()
+    })
+}
+
+// volumes of a series share the title, so volume is shown after it
+fn display_title(title: String, volume: Option<&str>) -> String {
+    match volume {
+        Some(volume) if !title.ends_with(volume) => format!("{title} {volume}"),
+        _ => title,
+    }
+}
+
+// text of element, or of rdf:value or foaf:name inside nested description
+fn rdf_text(node: Node) -> Option<String> {
+    let text = node.text().map(str::trim).filter(|text| !text.is_empty());
+    let text = text.or_else(|| {
+        node.descendants()
+            .find(|node| node.has_tag_name("value") || node.has_tag_name("name"))?
+            .text()
+            .map(str::trim)
+    })?;
+
+    Some(text.to_string())
+}
+
+// build sru query, anywhere is used when no field is given
+fn search_query(any: &str, fields: &models::BookFields) -> Result<String, E> {
+    let mut clauses = vec!["mediatype=1".to_string()];
+
+    if !any.is_empty() || fields.is_empty() {
+        clauses.push(format!("anywhere={}", cql_quote(any)?));
+    }
+
+    let indexes = [
+        ("title", &fields.title),
+        ("creator", &fields.creator),
+        ("publisher", &fields.publisher),
+        ("subject", &fields.subject),
+    ];
+    for (index, value) in indexes {
+        if let Some(value) = value {
+            clauses.push(format!("{index}={}", cql_quote(value)?));
+        }
+    }
+
+    clauses.push("sortBy=\"issued_date/sort.descending\"".to_string());
+    Ok(clauses.join(" AND "))
+}
+
+// quoted cql term, quote and backslash in user text do not end the term
+fn cql_quote(text: &str) -> Result<String, E> {
+    let text = upstream::query_text(text)?;
+    let text = text.replace('\\', "\\\\").replace('"', "\\\"");
+    Ok(format!("\"{text}\""))
 }
 
 #[cfg(test)]
 mod test {
-    use super::NdlAppState;
+    use super::{creator_split, parse_book, search_query, NdlAppState, RecordSchema};
+    use crate::{error::Error, models::BookFields, upstream::Agent};
+    use actix_web::{
+        http::header::{FROM, USER_AGENT},
+        web, App, HttpRequest, HttpResponse,
+    };
+    use std::collections::HashMap;
+
+    const FIXTURE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<searchRetrieveResponse xmlns="http://www.loc.gov/zing/srw/">
+<version>1.2</version>
+<numberOfRecords>1</numberOfRecords>
+<nextRecordPosition>0</nextRecordPosition>
+<records>
+<record>
+<recordSchema>info:srw/schema/1/dcndl_simple</recordSchema>
+<recordPacking>xml</recordPacking>
+<recordData>
+<dcndl_simple:dc xmlns:dcndl_simple="http://ndl.go.jp/dcndl/dcndl_simple/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:dcterms="http://purl.org/dc/terms/" xmlns:dcndl="http://ndl.go.jp/dcndl/terms/" xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance">
+<dc:title>エリック・エヴァンスのドメイン駆動設計</dc:title>
+<dc:creator>Evans, Eric</dc:creator>
+<dc:creator>今関, 剛</dc:creator>
+<dc:publisher>翔泳社</dc:publisher>
+<dcterms:issued xsi:type="dcterms:W3CDTF">2011</dcterms:issued>
+<dc:subject>ソフトウェア開発</dc:subject>
+<dc:description>原タイトル: Domain-driven design</dc:description>
+<dc:identifier xsi:type="dcndl:ISBN">9784798121963</dc:identifier>
+<dc:language xsi:type="dcterms:ISO639-2">jpn</dc:language>
+</dcndl_simple:dc>
+</recordData>
+<recordPosition>1</recordPosition>
+</record>
+</records>
+</searchRetrieveResponse>"#;
+
+    async fn sru() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("application/xml")
+            .body(FIXTURE)
+    }
+
+    #[test]
+    fn test_parse_book() {
+        let document = roxmltree::Document::parse(FIXTURE).unwrap();
+        let chunk = parse_book(document.root_element(), RecordSchema::Simple, false).unwrap();
+        assert_eq!(chunk.total_count, 1);
+
+        let item = &chunk.items[0];
+        assert_eq!(item.title, "エリック・エヴァンスのドメイン駆動設計");
+        assert_eq!(item.creators, vec!["Evans, Eric", "今関, 剛"]);
+        assert_eq!(item.publishers, vec!["翔泳社"]);
+        assert_eq!(item.keywords, vec!["ソフトウェア開発"]);
+        assert_eq!(item.annotations, vec!["原タイトル: Domain-driven design"]);
+        assert_eq!(item.issued_at.as_deref(), Some("2011"));
+        assert_eq!(item.isbn.as_deref(), Some("9784798121963"));
+        assert_eq!(item.language.as_deref(), Some("jpn"));
+    }
+
+    const FULL: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<searchRetrieveResponse xmlns="http://www.loc.gov/zing/srw/">
+<version>1.2</version>
+<numberOfRecords>1</numberOfRecords>
+<records>
+<record>
+<recordSchema>info:srw/schema/1/dcndl</recordSchema>
+<recordPacking>xml</recordPacking>
+<recordData>
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#" xmlns:dcterms="http://purl.org/dc/terms/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:dcndl="http://ndl.go.jp/dcndl/terms/" xmlns:foaf="http://xmlns.com/foaf/0.1/">
+<dcndl:BibAdminResource rdf:about="https://iss.ndl.go.jp/books/R100000002-I000011164379-00">
+<dcndl:record rdf:resource="https://iss.ndl.go.jp/books/R100000002-I000011164379-00#material"/>
+</dcndl:BibAdminResource>
+<dcndl:BibResource rdf:about="https://iss.ndl.go.jp/books/R100000002-I000011164379-00#material">
+<dcterms:identifier rdf:datatype="http://ndl.go.jp/dcndl/terms/ISBN">978-4-7981-2196-3</dcterms:identifier>
+<dc:title>
+<rdf:Description>
+<rdf:value>エリック・エヴァンスのドメイン駆動設計</rdf:value>
+<dcndl:transcription>エリック エヴァンス ノ ドメイン クドウ セッケイ</dcndl:transcription>
+</rdf:Description>
+</dc:title>
+<dcndl:seriesTitle>
+<rdf:Description>
+<rdf:value>IT Architects' archive</rdf:value>
+</rdf:Description>
+</dcndl:seriesTitle>
+<dcterms:creator>
+<foaf:Agent rdf:about="http://id.ndl.go.jp/auth/entity/00000000">
+<foaf:name>Evans, Eric</foaf:name>
+</foaf:Agent>
+</dcterms:creator>
+<dcterms:publisher>
+<foaf:Agent>
+<foaf:name>翔泳社</foaf:name>
+</foaf:Agent>
+</dcterms:publisher>
+<dcndl:edition>新装版</dcndl:edition>
+<dcterms:issued rdf:datatype="http://purl.org/dc/terms/W3CDTF">2011.4</dcterms:issued>
+<dcterms:subject>
+<rdf:Description rdf:about="http://id.ndl.go.jp/auth/ndlsh/00000000">
+<rdf:value>ソフトウェア開発</rdf:value>
+</rdf:Description>
+</dcterms:subject>
+<dcterms:language rdf:datatype="http://purl.org/dc/terms/ISO639-2">jpn</dcterms:language>
+<dcterms:extent>xxx, 554p ; 24cm</dcterms:extent>
+<dcndl:price>5200円</dcndl:price>
+</dcndl:BibResource>
+</rdf:RDF>
+</recordData>
+<recordPosition>1</recordPosition>
+</record>
+</records>
+</searchRetrieveResponse>"#;
+
+    #[test]
+    fn test_parse_book_full() {
+        let document = roxmltree::Document::parse(FULL).unwrap();
+        let chunk = parse_book(document.root_element(), RecordSchema::Full, false).unwrap();
+        assert_eq!(chunk.total_count, 1);
+
+        let item = &chunk.items[0];
+        assert_eq!(item.title, "エリック・エヴァンスのドメイン駆動設計");
+        assert_eq!(item.creators, vec!["Evans, Eric"]);
+        assert_eq!(item.publishers, vec!["翔泳社"]);
+        assert_eq!(item.keywords, vec!["ソフトウェア開発"]);
+        assert_eq!(item.issued_at.as_deref(), Some("2011-04"));
+        assert_eq!(item.isbn.as_deref(), Some("9784798121963"));
+        assert_eq!(item.language.as_deref(), Some("jpn"));
+        assert_eq!(item.series_title.as_deref(), Some("IT Architects' archive"));
+        assert!(item.volume.is_none());
+        assert_eq!(item.edition.as_deref(), Some("新装版"));
+        assert_eq!(item.extent.as_deref(), Some("xxx, 554p ; 24cm"));
+        assert_eq!(item.price.as_deref(), Some("5200円"));
+
+        // simple record has none of them, and they are not serialized
+        let document = roxmltree::Document::parse(FIXTURE).unwrap();
+        let chunk = parse_book(document.root_element(), RecordSchema::Simple, false).unwrap();
+        assert!(chunk.items[0].series_title.is_none());
+        let value = serde_json::to_value(&chunk.items[0]).unwrap();
+        assert!(value.get("seriesTitle").is_none());
+        assert!(value.get("volume").is_none());
+    }
+
+    const VOLUME: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<searchRetrieveResponse xmlns="http://www.loc.gov/zing/srw/">
+<version>1.2</version>
+<numberOfRecords>1</numberOfRecords>
+<records>
+<record>
+<recordSchema>info:srw/schema/1/dcndl</recordSchema>
+<recordPacking>xml</recordPacking>
+<recordData>
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#" xmlns:dcterms="http://purl.org/dc/terms/" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:dcndl="http://ndl.go.jp/dcndl/terms/" xmlns:foaf="http://xmlns.com/foaf/0.1/">
+<dcndl:BibResource rdf:about="https://iss.ndl.go.jp/books/R100000002-I025951470-00#material">
+<dcterms:identifier rdf:datatype="http://ndl.go.jp/dcndl/terms/ISBN">978-4-8222-9842-5</dcterms:identifier>
+<dc:title>
+<rdf:Description>
+<rdf:value>コンピュータの構成と設計 : ハードウエアとソフトウエアのインタフェース</rdf:value>
+</rdf:Description>
+</dc:title>
+<dcndl:volume>
+<rdf:Description>
+<rdf:value>上</rdf:value>
+<dcndl:transcription>ジョウ</dcndl:transcription>
+</rdf:Description>
+</dcndl:volume>
+<dcndl:seriesTitle>
+<rdf:Description>
+<rdf:value>Computer Organization and Design</rdf:value>
+</rdf:Description>
+</dcndl:seriesTitle>
+<dcterms:creator>
+<foaf:Agent>
+<foaf:name>Patterson, David A.</foaf:name>
+</foaf:Agent>
+</dcterms:creator>
+<dcterms:publisher>
+<foaf:Agent>
+<foaf:name>日経BP社</foaf:name>
+</foaf:Agent>
+</dcterms:publisher>
+<dcndl:edition>第5版</dcndl:edition>
+<dcterms:issued rdf:datatype="http://purl.org/dc/terms/W3CDTF">2014.12</dcterms:issued>
+</dcndl:BibResource>
+</rdf:RDF>
+</recordData>
+<recordPosition>1</recordPosition>
+</record>
+</records>
+</searchRetrieveResponse>"#;
+
+    #[test]
+    fn test_parse_book_volume() {
+        let document = roxmltree::Document::parse(VOLUME).unwrap();
+        let chunk = parse_book(document.root_element(), RecordSchema::Full, false).unwrap();
+
+        let item = &chunk.items[0];
+        assert_eq!(item.volume.as_deref(), Some("上"));
+        assert_eq!(
+            item.title,
+            "コンピュータの構成と設計 : ハードウエアとソフトウエアのインタフェース 上"
+        );
+        assert_eq!(
+            item.series_title.as_deref(),
+            Some("Computer Organization and Design")
+        );
+
+        let value = serde_json::to_value(item).unwrap();
+        assert_eq!(value["volume"], "上");
+        assert_eq!(value["seriesTitle"], "Computer Organization and Design");
+    }
+
+    #[test]
+    fn test_creator_split() {
+        let creator = creator_split("山田太郎 著");
+        assert_eq!(creator.name, "山田太郎");
+        assert_eq!(creator.role.as_deref(), Some("著"));
+
+        // full width space, bracket and combined role
+        for (text, name, role) in [
+            ("山田太郎　訳", "山田太郎", "訳"),
+            ("山田, 太郎 [監修]", "山田, 太郎", "監修"),
+            ("なかがわりえこ 作・絵", "なかがわりえこ", "作・絵"),
+        ] {
+            let creator = creator_split(text);
+            assert_eq!(creator.name, name);
+            assert_eq!(creator.role.as_deref(), Some(role));
+        }
+
+        // unknown role or name only is kept as is
+        for text in ["Evans, Eric", "山田太郎 ほか", "著", "山田太郎 著・ほか"] {
+            let creator = creator_split(text);
+            assert_eq!(creator.name, text);
+            assert!(creator.role.is_none());
+        }
+
+        // raw creators unless enabled
+        let xml = FIXTURE.replace("今関, 剛", "今関, 剛 訳");
+        let document = roxmltree::Document::parse(&xml).unwrap();
+        let chunk = parse_book(document.root_element(), RecordSchema::Simple, false).unwrap();
+        assert_eq!(chunk.items[0].creators, vec!["Evans, Eric", "今関, 剛 訳"]);
+        assert!(chunk.items[0].creator_roles.is_empty());
+
+        let chunk = parse_book(document.root_element(), RecordSchema::Simple, true).unwrap();
+        let item = &chunk.items[0];
+        assert_eq!(item.creators, vec!["Evans, Eric", "今関, 剛"]);
+        assert_eq!(item.creator_roles[1].role.as_deref(), Some("訳"));
+        assert!(item.creator_roles[0].role.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_ndl_mock() {
+        let srv = actix_test::start(|| App::new().route("/api/sru", web::get().to(sru)));
+        let app = NdlAppState::new().with_base_url(&format!("http://{}", srv.addr()));
+
+        let res = app
+            .book_query_fields("ドメイン駆動設計", &BookFields::default(), 20, 0)
+            .await
+            .unwrap();
+        assert_eq!(res.total_count, 1);
+        assert_eq!(res.page_info.total_pages, 1);
+
+        let res = app.book_get("9784798121963").await.unwrap();
+        assert_eq!(res.title, "エリック・エヴァンスのドメイン駆動設計");
+    }
+
+    // only subject scoped query finds the book
+    async fn sru_subject(query: web::Query<HashMap<String, String>>) -> HttpResponse {
+        match query["query"].contains("subject=\"ソフトウェア開発\"") {
+            true => sru().await,
+            false => empty().await,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_ndl_subject() {
+        let srv = actix_test::start(|| App::new().route("/api/sru", web::get().to(sru_subject)));
+        let app = NdlAppState::new().with_base_url(&format!("http://{}", srv.addr()));
+
+        let fields = BookFields {
+            subject: Some("ソフトウェア開発".to_string()),
+            ..Default::default()
+        };
+        let res = app.book_query_fields("", &fields, 20, 0).await.unwrap();
+        assert_eq!(res.items.len(), 1);
+        assert!(res.items[0]
+            .keywords
+            .iter()
+            .any(|text| text.contains("ソフトウェア開発")));
+
+        let res = app
+            .book_query_fields("", &BookFields::default(), 20, 0)
+            .await
+            .unwrap();
+        assert!(res.items.is_empty());
+    }
+
+    const EMPTY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<searchRetrieveResponse xmlns="http://www.loc.gov/zing/srw/">
+<version>1.2</version>
+<numberOfRecords>0</numberOfRecords>
+</searchRetrieveResponse>"#;
+
+    // maintenance page served instead of sru response
+    const MALFORMED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<html><body>メンテナンス中</body></html>"#;
+
+    async fn empty() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("application/xml")
+            .body(EMPTY)
+    }
+
+    async fn malformed() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("application/xml")
+            .body(MALFORMED)
+    }
+
+    #[actix_web::test]
+    async fn test_ndl_empty() {
+        let srv = actix_test::start(|| App::new().route("/api/sru", web::get().to(empty)));
+        let app = NdlAppState::new().with_base_url(&format!("http://{}", srv.addr()));
+
+        let res = app
+            .book_query_fields("存在しない本", &BookFields::default(), 20, 0)
+            .await
+            .unwrap();
+        assert!(res.items.is_empty());
+        assert_eq!(res.total_count, 0);
+
+        let err = app.book_get("9784798121963").await.unwrap_err();
+        assert!(matches!(err, Error::NotFound));
+
+        let srv = actix_test::start(|| App::new().route("/api/sru", web::get().to(malformed)));
+        let app = NdlAppState::new().with_base_url(&format!("http://{}", srv.addr()));
+
+        let err = app.book_get("9784798121963").await.unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+
+        let err = app
+            .book_query_fields("ドメイン駆動設計", &BookFields::default(), 20, 0)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Parse(_)));
+    }
+
+    #[actix_web::test]
+    async fn test_ndl_agent() {
+        let srv = actix_test::start(|| {
+            App::new().route(
+                "/api/sru",
+                web::get().to(|req: HttpRequest| async move {
+                    let header = |name| {
+                        req.headers()
+                            .get(name)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default()
+                            .to_string()
+                    };
+                    // echo identification back as title
+                    let title = format!("{} {}", header(USER_AGENT), header(FROM));
+                    HttpResponse::Ok()
+                        .content_type("application/xml")
+                        .body(FIXTURE.replace("エリック・エヴァンスのドメイン駆動設計", &title))
+                }),
+            )
+        });
+        let app = NdlAppState::new().with_base_url(&format!("http://{}", srv.addr()));
+
+        let res = app.book_get("9784798121963").await.unwrap();
+        assert!(res.title.starts_with("libres-api/"));
+
+        let agent = Agent::new("libres-test/1.0")
+            .unwrap()
+            .with_contact("ops@example.com")
+            .unwrap();
+        let app = app.with_agent(agent);
+        let res = app.book_get("9784798121963").await.unwrap();
+        assert_eq!(res.title, "libres-test/1.0 ops@example.com");
+    }
+
+    #[actix_web::test]
+    async fn test_ndl_thumbnail() {
+        let srv = actix_test::start(|| App::new().route("/api/sru", web::get().to(sru)));
+        let app = NdlAppState::new().with_base_url(&format!("http://{}", srv.addr()));
+
+        // url is synthesized from isbn, unverified
+        let res = app.book_get("9784798121963").await.unwrap();
+        assert_eq!(
+            res.image_url.as_deref(),
+            Some("https://iss.ndl.go.jp/thumbnail/9784798121963")
+        );
+    }
+
+    #[test]
+    fn test_search_query() {
+        let query = search_query("ドメイン駆動設計", &BookFields::default()).unwrap();
+        assert_eq!(
+            query,
+            "mediatype=1 AND anywhere=\"ドメイン駆動設計\" AND sortBy=\"issued_date/sort.descending\""
+        );
+
+        let fields = BookFields {
+            creator: Some("Evans".to_string()),
+            ..Default::default()
+        };
+        let query = search_query("", &fields).unwrap();
+        assert_eq!(
+            query,
+            "mediatype=1 AND creator=\"Evans\" AND sortBy=\"issued_date/sort.descending\""
+        );
+
+        let fields = BookFields {
+            subject: Some("ソフトウェア開発".to_string()),
+            ..Default::default()
+        };
+        let query = search_query("設計", &fields).unwrap();
+        assert_eq!(
+            query,
+            "mediatype=1 AND anywhere=\"設計\" AND subject=\"ソフトウェア開発\" AND sortBy=\"issued_date/sort.descending\""
+        );
+
+        // quote in user text stays inside the term, control characters are dropped
+        let fields = BookFields {
+            title: Some("a\" OR title=\"b".to_string()),
+            ..Default::default()
+        };
+        let query = search_query("x\\\ny", &fields).unwrap();
+        assert_eq!(
+            query,
+            r#"mediatype=1 AND anywhere="x\\y" AND title="a\" OR title=\"b" AND sortBy="issued_date/sort.descending""#
+        );
+
+        assert!(search_query(&"a".repeat(201), &BookFields::default()).is_err());
+    }
 
     #[actix_web::test]
     async fn test_ndl() {
         let app = NdlAppState::new();
 
-        let res = app.book_query("ドメイン駆動設計", 20, 0).await.unwrap();
+        let res = app
+            .book_query_fields("ドメイン駆動設計", &BookFields::default(), 20, 0)
+            .await
+            .unwrap();
         println!("book query: \"{res:?}\"");
         println!("book query count: \"{:?}\"", res.items.len());
 
         let res = app.book_get("9784798121963").await.unwrap();
         println!("book get: \"{res:?}\"");
+
+        let fields = BookFields {
+            subject: Some("ソフトウェア開発".to_string()),
+            ..Default::default()
+        };
+        let res = app.book_query_fields("", &fields, 20, 0).await.unwrap();
+        assert!(!res.items.is_empty());
+        assert!(res.items.iter().all(|item| item
+            .keywords
+            .iter()
+            .any(|text| text.contains("ソフトウェア開発"))));
     }
 }
@@ -0,0 +1,385 @@
+use crate::{
+    error::Error,
+    issued, models,
+    upstream::{Agent, Breaker, BreakerStatus, Limiter, Retry},
+};
+use actix_web::web::Buf;
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+
+type E = Error;
+
+const BASE_URL: &str = "https://api.openbd.jp";
+
+#[derive(Debug, Clone)]
+pub struct OpenBdAppState {
+    coverage: Arc<RwLock<Vec<String>>>,
+    base_url: String,
+    limiter: Limiter,
+    retry: Retry,
+    breaker: Breaker,
+    agent: Agent,
+}
+
+impl Default for OpenBdAppState {
+    fn default() -> Self {
+        Self {
+            coverage: Default::default(),
+            base_url: BASE_URL.to_string(),
+            limiter: Default::default(),
+            retry: Default::default(),
+            breaker: Default::default(),
+            agent: Default::default(),
+        }
+    }
+}
+
+impl OpenBdAppState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limiter(self, limiter: Limiter) -> Self {
+        Self { limiter, ..self }
+    }
+
+    pub fn with_retry(self, retry: Retry) -> Self {
+        Self { retry, ..self }
+    }
+
+    pub fn with_breaker(self, breaker: Breaker) -> Self {
+        Self { breaker, ..self }
+    }
+
+    pub fn with_agent(self, agent: Agent) -> Self {
+        Self { agent, ..self }
+    }
+
+    pub fn breaker_status(&self) -> BreakerStatus {
+        self.breaker.status()
+    }
+
+    // point to another host, e.g. local mock server
+    pub fn with_base_url(self, base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            ..self
+        }
+    }
+
+    // search book by isbn prefix (e.g. publisher code)
+    // openbd has no full text search, so resolve isbn from coverage and fetch them
+    pub async fn book_query(
+        &self,
+        any: &str,
+        page_size: u32,
+        page: u32,
+    ) -> Result<models::BookChunk, E> {
+        let prefix: String = any.chars().filter(|c| c.is_ascii_digit()).collect();
+
+        if prefix.is_empty() {
+            return Ok(models::BookChunk {
+                page_info: models::PageInfo::new(page, page_size, 0),
+                ..Default::default()
+            });
+        }
+
+        if self.coverage.read().map_err(Error::poisoned)?.is_empty() {
+            self.pull_coverage().await?;
+        }
+
+        let (isbns, total_count) = {
+            let coverage = self.coverage.read().map_err(Error::poisoned)?;
+
+            let filtered = coverage.iter().filter(|isbn| isbn.starts_with(&prefix));
+
+            let isbns: Vec<_> = filtered
+                .clone()
+                .skip((page as usize).saturating_mul(page_size as usize))
+                .take(page_size as usize)
+                .cloned()
+                .collect();
+
+            (isbns, filtered.count() as u32)
+        };
+
+        let items = match isbns.is_empty() {
+            true => vec![],
+            false => self.book_fetch(&isbns).await?,
+        };
+
+        Ok(models::BookChunk {
+            items,
+            total_count,
+            page_info: models::PageInfo::new(page, page_size, total_count),
+        })
+    }
+
+    pub async fn book_get(&self, isbn: &str) -> Result<models::Book, E> {
+        let mut items = self.book_fetch(&[isbn.to_string()]).await?;
+
+        let item = items.pop().ok_or(Error::NotFound)?;
+
+        Ok(item)
+    }
+
+    // get and store all isbn covered by openbd
+    async fn pull_coverage(&self) -> Result<(), E> {
+        let _permit = self.limiter.acquire().await?;
+
+        let request = self
+            .agent
+            .client()
+            .get(format!("{}/v1/coverage", self.base_url));
+        let reader = self
+            .retry
+            .send(request)
+            .await?
+            .body()
+            .limit(1024 * 1024 * 64) // 64Mib
+            .await?
+            .reader();
+
+        let result: Vec<String> = serde_json::from_reader(reader)?;
+
+        let mut coverage = self.coverage.write().map_err(Error::poisoned)?;
+        *coverage = result;
+        Ok(())
+    }
+
+    async fn book_fetch(&self, isbns: &[String]) -> Result<Vec<models::Book>, E> {
+        let _permit = self.limiter.acquire().await?;
+
+        let request = self
+            .agent
+            .client()
+            .get(format!("{}/v1/get", self.base_url))
+            .query(&[("isbn", isbns.join(",").as_str())])?;
+        let reader = self
+            .breaker
+            .send(&self.retry, request)
+            .await?
+            .body()
+            .await?
+            .reader();
+
+        let root = serde_json::from_reader(reader)?;
+        let items = parse_book(root).ok_or(Error::Parse("no book list".to_string()))?;
+
+        Ok(items)
+    }
+}
+
+fn parse_book(node: Value) -> Option<Vec<models::Book>> {
+    let items = node
+        .as_array()?
+        .iter()
+        .filter_map(|node| {
+            let summary = node.get("summary")?;
+            let onix = node.get("onix")?;
+            let detail = onix.get("DescriptiveDetail");
+
+            let title = detail
+                .and_then(|node| node.get("TitleDetail"))
+                .and_then(|node| node.get("TitleElement"))
+                .and_then(|node| node.get("TitleText"))
+                .and_then(|node| node.get("content"))
+                .or_else(|| summary.get("title"))?
+                .as_str()?
+                .to_string();
+
+            let creators = detail
+                .and_then(|node| node.get("Contributor"))
+                .and_then(|node| node.as_array())
+                .map(|node| {
+                    node.iter()
+                        .filter_map(|node| node.get("PersonName")?.get("content")?.as_str())
+                        .map(|text| text.to_string())
+                        .collect()
+                })
+                .unwrap_or(vec![]);
+
+            let publishers = summary
+                .get("publisher")
+                .and_then(|node| node.as_str())
+                .filter(|text| !text.is_empty())
+                .map(|text| vec![text.to_string()])
+                .unwrap_or(vec![]);
+
+            let issued_at = summary
+                .get("pubdate")
+                .and_then(|node| node.as_str())
+                .filter(|text| !text.is_empty())
+                .map(|text| issued::normalize(text).unwrap_or_else(|| text.to_string()));
+
+            let year = issued_at.as_deref().and_then(issued::year);
+
+            let keywords = detail
+                .and_then(|node| node.get("Subject"))
+                .and_then(|node| node.as_array())
+                .map(|node| {
+                    node.iter()
+                        .filter_map(|node| node.get("SubjectHeadingText")?.as_str())
+                        .map(|text| text.to_string())
+                        .collect()
+                })
+                .unwrap_or(vec![]);
+
+            // TextType: 02 short description, 03 description, 04 table of contents
+            let text_contents: Vec<_> = onix
+                .get("CollateralDetail")
+                .and_then(|node| node.get("TextContent"))
+                .and_then(|node| node.as_array())
+                .map(|node| {
+                    node.iter()
+                        .filter_map(|node| {
+                            let text_type = node.get("TextType")?.as_str()?;
+                            let text = node.get("Text")?.as_str()?;
+                            Some((text_type, text.to_string()))
+                        })
+                        .collect()
+                })
+                .unwrap_or(vec![]);
+
+            let descriptions = text_contents
+                .iter()
+                .filter(|(text_type, _)| *text_type == "02" || *text_type == "03")
+                .map(|(_, text)| text.clone())
+                .collect();
+
+            let annotations = text_contents
+                .iter()
+                .filter(|(text_type, _)| *text_type == "04")
+                .map(|(_, text)| text.clone())
+                .collect();
+
+            let language = detail
+                .and_then(|node| node.get("Language"))
+                .and_then(|node| node.as_array())
+                .and_then(|node| node.first())
+                .and_then(|node| node.get("LanguageCode"))
+                .and_then(|node| node.as_str())
+                .map(|text| text.to_string());
+
+            let isbn = summary
+                .get("isbn")
+                .and_then(|node| node.as_str())
+                .map(|text| text.to_string());
+
+            let image_url = summary
+                .get("cover")
+                .and_then(|node| node.as_str())
+                .filter(|text| !text.is_empty())
+                .map(|text| text.to_string());
+
+            Some(models::Book {
+                title,
+                descriptions,
+                keywords,
+                creators,
+                publishers,
+                issued_at,
+                year,
+                isbn,
+                language,
+                annotations,
+                image_url,
+                ..Default::default()
+            })
+        })
+        .collect();
+
+    Some(items)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_book, OpenBdAppState};
+    use actix_web::{web, App, HttpResponse};
+
+    const FIXTURE: &str = r#"[
+        {
+            "onix": {
+                "RecordReference": "9784798121963",
+                "DescriptiveDetail": {
+                    "Language": [{ "LanguageCode": "jpn", "LanguageRole": "01" }],
+                    "TitleDetail": {
+                        "TitleType": "01",
+                        "TitleElement": {
+                            "TitleElementLevel": "01",
+                            "TitleText": { "collationkey": "ドメインクドウセッケイ", "content": "エリック・エヴァンスのドメイン駆動設計" }
+                        }
+                    },
+                    "Contributor": [
+                        { "SequenceNumber": "1", "ContributorRole": ["A01"], "PersonName": { "content": "Evans,Eric" } },
+                        { "SequenceNumber": "2", "ContributorRole": ["B06"], "PersonName": { "content": "今関剛" } }
+                    ],
+                    "Subject": [
+                        { "SubjectSchemeIdentifier": "78", "SubjectCode": "3055" }
+                    ]
+                },
+                "CollateralDetail": {
+                    "TextContent": [
+                        { "TextType": "03", "ContentAudience": "00", "Text": "ソフトウェア開発の手法を解説。" },
+                        { "TextType": "04", "ContentAudience": "00", "Text": "第1部 ドメインモデルを機能させる" }
+                    ]
+                }
+            },
+            "summary": {
+                "isbn": "9784798121963",
+                "title": "エリック・エヴァンスのドメイン駆動設計",
+                "volume": "",
+                "series": "",
+                "publisher": "翔泳社",
+                "pubdate": "2011-04",
+                "cover": "https://cover.openbd.jp/9784798121963.jpg",
+                "author": "Evans,Eric／著 今関剛／監訳"
+            }
+        },
+        null
+    ]"#;
+
+    #[test]
+    fn test_parse_book() {
+        let root = serde_json::from_str(FIXTURE).unwrap();
+        let items = parse_book(root).unwrap();
+        assert_eq!(items.len(), 1);
+
+        let item = &items[0];
+        assert_eq!(item.title, "エリック・エヴァンスのドメイン駆動設計");
+        assert_eq!(item.creators, vec!["Evans,Eric", "今関剛"]);
+        assert_eq!(item.publishers, vec!["翔泳社"]);
+        assert_eq!(item.descriptions, vec!["ソフトウェア開発の手法を解説。"]);
+        assert_eq!(item.annotations, vec!["第1部 ドメインモデルを機能させる"]);
+        assert_eq!(item.issued_at.as_deref(), Some("2011-04"));
+        assert_eq!(item.language.as_deref(), Some("jpn"));
+        assert_eq!(
+            item.image_url.as_deref(),
+            Some("https://cover.openbd.jp/9784798121963.jpg")
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_book_query_far_page() {
+        let srv = actix_test::start(|| {
+            App::new().route(
+                "/v1/coverage",
+                web::get().to(|| async { HttpResponse::Ok().json(["9784798121963"]) }),
+            )
+        });
+        let app = OpenBdAppState::new().with_base_url(&format!("http://{}", srv.addr()));
+
+        // page past the end is empty, nothing is fetched
+        let res = app.book_query("978", 20, u32::MAX).await.unwrap();
+        assert!(res.items.is_empty());
+        assert_eq!(res.total_count, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_openbd() {
+        let app = OpenBdAppState::new();
+
+        let res = app.book_get("9784798121963").await.unwrap();
+        println!("book get: \"{res:?}\"");
+    }
+}
@@ -1,21 +1,72 @@
-use crate::models;
+use crate::{
+    error::Error,
+    issued, models,
+    upstream::{Agent, Breaker, BreakerStatus, Limiter, Retry},
+};
 use actix_web::web::Buf;
-use anyhow::Context;
-use awc::Client;
+use awc::ClientRequest;
 use serde_json::Value;
-use std::error::Error;
 
-type E = Box<dyn Error>;
+type E = Error;
 
-#[derive(Debug, Default, Clone)]
+const BASE_URL: &str = "https://app.rakuten.co.jp";
+
+#[derive(Debug, Clone)]
 pub struct RakutenAppState {
     appkey: String,
+    base_url: String,
+    limiter: Limiter,
+    retry: Retry,
+    breaker: Breaker,
+    agent: Agent,
+}
+
+impl Default for RakutenAppState {
+    fn default() -> Self {
+        Self {
+            appkey: String::new(),
+            base_url: BASE_URL.to_string(),
+            limiter: Default::default(),
+            retry: Default::default(),
+            breaker: Default::default(),
+            agent: Default::default(),
+        }
+    }
 }
 
 impl RakutenAppState {
     pub fn new(appkey: &str) -> Self {
         Self {
             appkey: appkey.to_string(),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_limiter(self, limiter: Limiter) -> Self {
+        Self { limiter, ..self }
+    }
+
+    pub fn with_retry(self, retry: Retry) -> Self {
+        Self { retry, ..self }
+    }
+
+    pub fn with_breaker(self, breaker: Breaker) -> Self {
+        Self { breaker, ..self }
+    }
+
+    pub fn with_agent(self, agent: Agent) -> Self {
+        Self { agent, ..self }
+    }
+
+    pub fn breaker_status(&self) -> BreakerStatus {
+        self.breaker.status()
+    }
+
+    // point to another host, e.g. local mock server
+    pub fn with_base_url(self, base_url: &str) -> Self {
+        Self {
+            base_url: base_url.to_string(),
+            ..self
         }
     }
 
@@ -25,47 +76,79 @@ impl RakutenAppState {
         page_size: u32,
         page: u32,
     ) -> Result<models::BookChunk, E> {
-        let page_size = page_size.to_string();
-        let page = (page + 1).to_string();
+        let request = self.book_query_request(any, page_size, page)?;
 
-        let reader = Client::default()
-            .get("https://app.rakuten.co.jp/services/api/BooksBook/Search/20170404")
-            .query(&[
-                ("applicationId", self.appkey.as_str()),
-                ("title", any),
-                ("hits", page_size.as_str()),
-                ("page", page.as_str()),
-            ])?
-            .send()
+        let _permit = self.limiter.acquire().await?;
+
+        let reader = self
+            .breaker
+            .send(&self.retry, request)
             .await?
             .body()
             .await?
             .reader();
 
         let root = serde_json::from_reader(reader)?;
-        let result = parse_book(root).context("failed to parse")?;
+        let mut result = parse_book(root).ok_or(Error::Parse("no book list".to_string()))?;
+        result.page_info = models::PageInfo::new(page, page_size, result.total_count);
 
         Ok(result)
     }
 
+    // request of book query, also to explain what is sent
+    pub fn book_query_request(
+        &self,
+        any: &str,
+        page_size: u32,
+        page: u32,
+    ) -> Result<ClientRequest, E> {
+        let hits = page_size.to_string();
+        let page_number = (page + 1).to_string();
+
+        let request = self
+            .agent
+            .client()
+            .get(format!(
+                "{}/services/api/BooksBook/Search/20170404",
+                self.base_url
+            ))
+            .query(&[
+                ("applicationId", self.appkey.as_str()),
+                ("title", any),
+                ("hits", hits.as_str()),
+                ("page", page_number.as_str()),
+            ])?;
+
+        Ok(request)
+    }
+
     pub async fn book_get(&self, isbn: &str) -> Result<models::Book, E> {
-        let reader = Client::default()
-            .get("https://app.rakuten.co.jp/services/api/BooksBook/Search/20170404")
+        let _permit = self.limiter.acquire().await?;
+
+        let request = self
+            .agent
+            .client()
+            .get(format!(
+                "{}/services/api/BooksBook/Search/20170404",
+                self.base_url
+            ))
             .query(&[
                 ("applicationId", self.appkey.as_str()),
                 ("isbn", isbn),
                 ("hits", "1"),
-            ])?
-            .send()
+            ])?;
+        let reader = self
+            .breaker
+            .send(&self.retry, request)
             .await?
             .body()
             .await?
             .reader();
 
         let root = serde_json::from_reader(reader)?;
-        let mut result = parse_book(root).context("failed to parse")?;
+        let mut result = parse_book(root).ok_or(Error::Parse("no book list".to_string()))?;
 
-        let item = result.items.pop().context("not found")?;
+        let item = result.items.pop().ok_or(Error::NotFound)?;
 
         Ok(item)
     }
@@ -96,7 +179,9 @@ fn parse_book(node: Value) -> Option<models::BookChunk> {
             let issued_at = node
                 .get("salesDate")
                 .and_then(|node| node.as_str())
-                .map(|text| text.to_string());
+                .map(|text| issued::normalize(text).unwrap_or_else(|| text.to_string()));
+
+            let year = issued_at.as_deref().and_then(issued::year);
 
             let keywords = vec![];
 
@@ -131,24 +216,65 @@ fn parse_book(node: Value) -> Option<models::BookChunk> {
                 creators,
                 publishers,
                 issued_at,
+                year,
                 isbn,
                 language,
                 annotations,
                 image_url,
+                ..Default::default()
             })
         })
         .collect();
 
     let total_count = node.get("count")?.as_i64()? as u32;
 
-    Some(models::BookChunk { items, total_count })
+    Some(models::BookChunk {
+        items,
+        total_count,
+        ..Default::default()
+    })
 }
 
 #[cfg(test)]
 mod test {
-    use super::RakutenAppState;
+    use super::{parse_book, RakutenAppState};
     use std::env;
 
+    const FIXTURE: &str = r#"{
+        "count": 1,
+        "page": 1,
+        "Items": [
+            {
+                "Item": {
+                    "title": "実践ドメイン駆動設計",
+                    "author": "ヴァーン・ヴァーノン/高木 正弘",
+                    "publisherName": "翔泳社",
+                    "size": "単行本",
+                    "isbn": "9784798131610",
+                    "itemCaption": "DDDを実践するための手引き。",
+                    "salesDate": "2015年03月",
+                    "smallImageUrl": "https://thumbnail.image.rakuten.co.jp/0_mall/book/cabinet/1610/9784798131610.jpg"
+                }
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse_book() {
+        let root = serde_json::from_str(FIXTURE).unwrap();
+        let chunk = parse_book(root).unwrap();
+        assert_eq!(chunk.total_count, 1);
+
+        let item = &chunk.items[0];
+        assert_eq!(item.title, "実践ドメイン駆動設計");
+        assert_eq!(item.creators, vec!["ヴァーン・ヴァーノン/高木 正弘"]);
+        assert_eq!(item.publishers, vec!["翔泳社"]);
+        assert_eq!(item.annotations, vec!["単行本"]);
+        assert_eq!(item.issued_at.as_deref(), Some("2015-03"));
+        assert_eq!(item.year, Some(2015));
+        assert_eq!(item.isbn.as_deref(), Some("9784798131610"));
+    }
+
     #[actix_web::test]
     async fn test_rakuten() {
         let appkey = env::var("RAKUTEN_APPKEY").unwrap();
@@ -0,0 +1,156 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+};
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// longer or non printable id from client is replaced with generated one
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+// id of request being handled, none outside of request e.g. on startup pull
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// take x-request-id of client or generate one, expose it to upstream calls
+// while handling and echo it in response
+pub async fn propagate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = req
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|text| is_valid(text))
+        .map(str::to_string)
+        .unwrap_or_else(generate);
+
+    let mut res = REQUEST_ID.scope(id.clone(), next.call(req)).await?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(X_REQUEST_ID, value);
+    }
+
+    Ok(res)
+}
+
+fn is_valid(text: &str) -> bool {
+    !text.is_empty() && text.len() <= MAX_LEN && text.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// random uuid v4
+fn generate() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{current, generate, propagate, X_REQUEST_ID};
+    use crate::upstream::Retry;
+    use actix_web::{
+        middleware::from_fn,
+        test::{call_service, init_service, read_body, TestRequest},
+        web::{get, Data},
+        App, HttpRequest, HttpResponse,
+    };
+    use awc::Client;
+
+    #[test]
+    fn test_generate() {
+        let id = generate();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert_ne!(id, generate());
+    }
+
+    #[actix_web::test]
+    async fn test_propagate() {
+        let app = init_service(App::new().wrap(from_fn(propagate)).route(
+            "/",
+            get().to(|| async { HttpResponse::Ok().body(current().unwrap_or_default()) }),
+        ))
+        .await;
+
+        // supplied id is seen by handler and echoed
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((X_REQUEST_ID, "client-42"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.headers().get(X_REQUEST_ID).unwrap(), "client-42");
+        assert_eq!(read_body(res).await, "client-42");
+
+        // missing or invalid id is generated
+        for req in [
+            TestRequest::get().uri("/"),
+            TestRequest::get()
+                .uri("/")
+                .insert_header((X_REQUEST_ID, "a".repeat(200))),
+        ] {
+            let res = call_service(&app, req.to_request()).await;
+            let id = res.headers().get(X_REQUEST_ID).unwrap().to_str().unwrap();
+            assert_eq!(id.len(), 36);
+            let id = id.to_string();
+            assert_eq!(read_body(res).await, id);
+        }
+
+        assert!(current().is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_propagate_upstream() {
+        let srv = actix_test::start(|| {
+            App::new().route(
+                "/echo",
+                get().to(|req: HttpRequest| async move {
+                    let id = req.headers().get(X_REQUEST_ID);
+                    let id = id.and_then(|id| id.to_str().ok()).unwrap_or_default();
+                    HttpResponse::Ok().body(id.to_string())
+                }),
+            )
+        });
+
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(propagate))
+                .app_data(Data::new(srv.url("/echo")))
+                .route(
+                    "/",
+                    get().to(|url: Data<String>| async move {
+                        let mut res = Retry::default()
+                            .send(Client::default().get(url.as_str()))
+                            .await
+                            .unwrap();
+                        HttpResponse::Ok().body(res.body().await.unwrap())
+                    }),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/")
+            .insert_header((X_REQUEST_ID, "client-42"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(read_body(res).await, "client-42");
+    }
+}
@@ -0,0 +1,72 @@
+use actix_web::{http::header::ACCEPT, HttpRequest, HttpResponse};
+use serde::Serialize;
+
+// serialize response body by accept header, json by default
+pub fn respond<T: Serialize>(req: &HttpRequest, value: &T) -> HttpResponse {
+    if !accepts_xml(req) {
+        return HttpResponse::Ok().json(value);
+    }
+
+    let Ok(body) = quick_xml::se::to_string_with_root("response", value) else {
+        return HttpResponse::InternalServerError().body("failed to serialize");
+    };
+
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(body)
+}
+
+fn accepts_xml(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|text| {
+            text.split(',')
+                .filter_map(|item| item.split(';').next())
+                .any(|mime| matches!(mime.trim(), "application/xml" | "text/xml"))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::respond;
+    use crate::models::Book;
+    use actix_web::{body::to_bytes, http::header::ACCEPT, test::TestRequest};
+
+    #[actix_web::test]
+    async fn test_respond() {
+        let book = Book {
+            title: "ドメイン駆動設計".to_string(),
+            creators: vec!["Evans,Eric".to_string()],
+            ..Default::default()
+        };
+
+        let req = TestRequest::default().to_http_request();
+        let res = respond(&req, &book);
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let body = to_bytes(res.into_body()).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["title"], "ドメイン駆動設計");
+
+        let req = TestRequest::default()
+            .insert_header((ACCEPT, "application/xml"))
+            .to_http_request();
+        let res = respond(&req, &book);
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "application/xml"
+        );
+        let body = to_bytes(res.into_body()).await.unwrap();
+        let text = std::str::from_utf8(&body).unwrap();
+        let document = roxmltree::Document::parse(text).unwrap();
+        let title = document
+            .root_element()
+            .children()
+            .find(|node| node.has_tag_name("title"))
+            .and_then(|node| node.text());
+        assert_eq!(title, Some("ドメイン駆動設計"));
+    }
+}
@@ -0,0 +1,719 @@
+use crate::{
+    cache::TtlCache,
+    error::{Error, Upstream},
+    request_id::{self, X_REQUEST_ID},
+};
+use actix_web::{
+    dev::{Decompress, Payload},
+    web::Query,
+};
+use awc::{
+    error::SendRequestError,
+    http::{header, header::HeaderValue, StatusCode},
+    Client, ClientRequest, ClientResponse,
+};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+type E = Error;
+
+pub type Response = ClientResponse<Decompress<Payload>>;
+
+// upper bound of wait between attempts, even if upstream asks longer
+const MAX_WAIT: Duration = Duration::from_secs(10);
+
+// same as awc default
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// image check gives up early, its answer is remembered for a while
+// found cover rarely disappears, missing one may be added soon
+const IMAGE_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+const FOUND_IMAGE_TTL: Duration = Duration::from_secs(60 * 60);
+const MISSING_IMAGE_TTL: Duration = Duration::from_secs(60 * 5);
+
+// placeholder such as 1x1 gif is smaller than any real cover
+const MIN_IMAGE_SIZE: u64 = 100;
+
+static FOUND_IMAGES: Lazy<TtlCache<String, ()>> = Lazy::new(|| TtlCache::new(FOUND_IMAGE_TTL));
+static MISSING_IMAGES: Lazy<TtlCache<String, ()>> = Lazy::new(|| TtlCache::new(MISSING_IMAGE_TTL));
+
+// user supplied text sent to upstream is limited to this many characters
+const MAX_QUERY_LEN: usize = 200;
+
+// query parameters carrying api key, masked when request is shown
+const SECRET_PARAMS: [&str; 4] = ["appid", "appkey", "applicationId", "key"];
+
+// identifies this service to external web api, so that provider can reach operator
+// contact is sent as from header, e.g. mail address
+#[derive(Debug, Clone)]
+pub struct Agent {
+    user_agent: HeaderValue,
+    contact: Option<HeaderValue>,
+}
+
+thread_local! {
+    // awc client is bound to its thread, so each worker builds one per agent and reuses it
+    static CLIENTS: RefCell<HashMap<(HeaderValue, Option<HeaderValue>), Client>> =
+        Default::default();
+}
+
+impl Default for Agent {
+    fn default() -> Self {
+        Self {
+            user_agent: HeaderValue::from_static(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            )),
+            contact: None,
+        }
+    }
+}
+
+impl Agent {
+    // invalid header value is refused here, awc would panic on every request
+    pub fn new(user_agent: &str) -> Result<Self, E> {
+        Ok(Self {
+            user_agent: header_value("user agent", user_agent)?,
+            contact: None,
+        })
+    }
+
+    pub fn with_contact(self, contact: &str) -> Result<Self, E> {
+        Ok(Self {
+            contact: Some(header_value("contact", contact)?),
+            ..self
+        })
+    }
+
+    pub fn client(&self) -> Client {
+        let key = (self.user_agent.clone(), self.contact.clone());
+
+        CLIENTS.with(|clients| {
+            clients
+                .borrow_mut()
+                .entry(key)
+                .or_insert_with(|| {
+                    let builder = Client::builder()
+                        .timeout(CLIENT_TIMEOUT)
+                        .add_default_header((header::USER_AGENT, self.user_agent.clone()));
+
+                    match &self.contact {
+                        Some(contact) => builder
+                            .add_default_header((header::FROM, contact.clone()))
+                            .finish(),
+                        None => builder.finish(),
+                    }
+                })
+                .clone()
+        })
+    }
+}
+
+fn header_value(name: &str, text: &str) -> Result<HeaderValue, E> {
+    HeaderValue::from_str(text).map_err(|_| Error::Config(format!("invalid {name}: {text:?}")))
+}
+
+// bound in-flight calls to an external web api
+#[derive(Debug, Clone)]
+pub struct Limiter {
+    semaphore: Arc<Semaphore>,
+    wait: Duration,
+}
+
+impl Default for Limiter {
+    fn default() -> Self {
+        Self::new(8, Duration::from_secs(5))
+    }
+}
+
+impl Limiter {
+    pub fn new(max_in_flight: usize, wait: Duration) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_in_flight)),
+            wait,
+        }
+    }
+
+    // wait briefly for a slot, fail with busy error when none is freed
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, E> {
+        let acquire = self.semaphore.clone().acquire_owned();
+
+        match actix_web::rt::time::timeout(self.wait, acquire).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(Upstream::Busy.into()),
+        }
+    }
+}
+
+// retry external web api call on transient failure
+#[derive(Debug, Clone)]
+pub struct Retry {
+    max_attempts: u32,
+    backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self::new(3, Duration::from_millis(200))
+    }
+}
+
+impl Retry {
+    pub fn new(max_attempts: u32, backoff: Duration) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            backoff,
+        }
+    }
+
+    // retry on connection error, 5xx and 429 with exponential backoff,
+    // other responses including 4xx are returned as is
+    pub async fn send(&self, request: ClientRequest) -> Result<Response, E> {
+        // id of client request being handled, to find this call in upstream logs
+        let request = match request_id::current() {
+            Some(id) => request.insert_header((X_REQUEST_ID, id)),
+            None => request,
+        };
+        let request = request.freeze()?;
+        let mut attempt = 1;
+
+        loop {
+            let backoff = self
+                .backoff
+                .saturating_mul(2u32.saturating_pow(attempt - 1));
+
+            let wait = match request.send().await {
+                Ok(response) if attempt < self.max_attempts && is_transient(response.status()) => {
+                    retry_after(&response).unwrap_or(backoff)
+                }
+                Err(err) if attempt < self.max_attempts && is_disconnected(&err) => backoff,
+                result => return Ok(result?),
+            };
+
+            attempt += 1;
+            actix_web::rt::time::sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+}
+
+// stop calling external web api which keeps failing
+// open after threshold consecutive failures within window, fail fast during
+// cooldown, then let one probe through and close again when it succeeds
+#[derive(Debug, Clone)]
+pub struct Breaker {
+    state: Arc<Mutex<BreakerState>>,
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    first_failure_at: Option<Instant>,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum BreakerStatus {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(60), Duration::from_secs(30))
+    }
+}
+
+impl Breaker {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            state: Default::default(),
+            threshold: threshold.max(1),
+            window,
+            cooldown,
+        }
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let Ok(state) = self.state.lock() else {
+            return BreakerStatus::Open;
+        };
+
+        match state.opened_at {
+            None => BreakerStatus::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => BreakerStatus::Open,
+            Some(_) => BreakerStatus::HalfOpen,
+        }
+    }
+
+    // request with retry inside, so that a failure counts once after all attempts
+    pub async fn send(&self, retry: &Retry, request: ClientRequest) -> Result<Response, E> {
+        self.call(retry.send(request)).await
+    }
+
+    // server error and connection failure count, other responses close circuit
+    pub async fn call<F>(&self, send: F) -> Result<Response, E>
+    where
+        F: Future<Output = Result<Response, E>>,
+    {
+        let mut probe = self.enter()?;
+
+        let result = send.await;
+        let failed = match &result {
+            Ok(response) => is_transient(response.status()),
+            Err(_) => true,
+        };
+        probe.breaker = None;
+        self.leave(failed);
+
+        result
+    }
+
+    fn enter(&self) -> Result<Probe<'_>, E> {
+        let mut state = self.state.lock().map_err(|_| Upstream::Unavailable)?;
+
+        match state.opened_at {
+            None => Ok(Probe { breaker: None }),
+            Some(opened_at) if opened_at.elapsed() < self.cooldown || state.probing => {
+                Err(Upstream::Unavailable.into())
+            }
+            Some(_) => {
+                state.probing = true;
+                Ok(Probe {
+                    breaker: Some(self),
+                })
+            }
+        }
+    }
+
+    fn leave(&self, failed: bool) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+
+        if !failed {
+            *state = BreakerState::default();
+            return;
+        }
+
+        // failed probe opens again for another cooldown
+        if state.probing {
+            state.probing = false;
+            state.opened_at = Some(Instant::now());
+            return;
+        }
+
+        let in_window = state
+            .first_failure_at
+            .is_some_and(|first_failure_at| first_failure_at.elapsed() < self.window);
+        if !in_window {
+            state.failures = 0;
+            state.first_failure_at = Some(Instant::now());
+        }
+
+        state.failures += 1;
+        if state.failures >= self.threshold {
+            state.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+// probe in flight, dropped without answer when its call is cancelled, e.g. client
+// disconnected or timed out, then next call may probe instead
+struct Probe<'a> {
+    breaker: Option<&'a Breaker>,
+}
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        let Some(breaker) = self.breaker else {
+            return;
+        };
+
+        if let Ok(mut state) = breaker.state.lock() {
+            state.probing = false;
+        }
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+fn is_disconnected(err: &SendRequestError) -> bool {
+    matches!(
+        err,
+        SendRequestError::Connect(_) | SendRequestError::Send(_) | SendRequestError::Timeout
+    )
+}
+
+// only delay-seconds form is supported
+fn retry_after(response: &Response) -> Option<Duration> {
+    let text = response.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+    let secs = text.trim().parse().ok()?;
+    Some(Duration::from_secs(secs))
+}
+
+// outgoing request shown to developer for troubleshooting
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpstreamRequest {
+    pub method: String,
+    pub url: String,
+    pub params: Vec<UpstreamParam>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpstreamParam {
+    pub name: String,
+    pub value: String,
+}
+
+impl UpstreamRequest {
+    pub fn new(request: &ClientRequest) -> Self {
+        let uri = request.get_uri();
+        let url = format!(
+            "{}://{}{}",
+            uri.scheme_str().unwrap_or("http"),
+            uri.authority()
+                .map(|authority| authority.as_str())
+                .unwrap_or_default(),
+            uri.path()
+        );
+
+        let params = uri
+            .query()
+            .and_then(|text| Query::<Vec<(String, String)>>::from_query(text).ok())
+            .map(|query| query.into_inner())
+            .unwrap_or_default()
+            .into_iter()
+            .map(
+                |(name, value)| match SECRET_PARAMS.contains(&name.as_str()) {
+                    true => UpstreamParam {
+                        name,
+                        value: "***".to_string(),
+                    },
+                    false => UpstreamParam { name, value },
+                },
+            )
+            .collect();
+
+        Self {
+            method: request.get_method().to_string(),
+            url,
+            params,
+        }
+    }
+}
+
+// whether url serves an image, missing image, placeholder or html error page is not
+// head is retried as ranged get for servers which do not allow head
+// only definite answers are cached, timeout and 5xx are checked again next time
+pub async fn image_exists(agent: &Agent, url: &str) -> bool {
+    let key = url.to_string();
+    if FOUND_IMAGES.get(&key).is_some() {
+        return true;
+    }
+    if MISSING_IMAGES.get(&key).is_some() {
+        return false;
+    }
+
+    let client = agent.client();
+    let response = match client.head(url).timeout(IMAGE_CHECK_TIMEOUT).send().await {
+        Ok(response) if response.status() == StatusCode::METHOD_NOT_ALLOWED => {
+            client
+                .get(url)
+                .timeout(IMAGE_CHECK_TIMEOUT)
+                .insert_header((header::RANGE, "bytes=0-0"))
+                .send()
+                .await
+        }
+        response => response,
+    };
+    let Ok(response) = response else {
+        return false;
+    };
+
+    let is_image = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|text| text.starts_with("image/"));
+
+    // ranged get tells whole size in content-range, unknown size is trusted
+    let size = response
+        .headers()
+        .get(header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|text| text.rsplit_once('/'))
+        .and_then(|(_, total)| total.parse::<u64>().ok())
+        .or_else(|| {
+            response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|text| text.parse::<u64>().ok())
+        });
+    let is_placeholder = size.is_some_and(|size| size < MIN_IMAGE_SIZE);
+
+    let exists = response.status().is_success() && is_image && !is_placeholder;
+    if exists {
+        FOUND_IMAGES.insert(key, ());
+    } else if !response.status().is_server_error() {
+        MISSING_IMAGES.insert(key, ());
+    }
+
+    exists
+}
+
+// user supplied text with control characters stripped, too long text is rejected
+pub fn query_text(text: &str) -> Result<String, E> {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+
+    if text.chars().count() > MAX_QUERY_LEN {
+        return Err(Error::Validation(format!(
+            "must be at most {MAX_QUERY_LEN} characters"
+        )));
+    }
+
+    Ok(text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        image_exists, Agent, Breaker, BreakerStatus, Limiter, Response, Retry, UpstreamRequest,
+    };
+    use crate::error::{Error, Upstream};
+    use actix_web::{web, App, HttpResponse};
+    use awc::{
+        http::{header, StatusCode},
+        Client,
+    };
+    use std::{
+        cell::Cell,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    // fail twice then succeed
+    async fn flaky(hits: web::Data<AtomicUsize>) -> HttpResponse {
+        match hits.fetch_add(1, Ordering::SeqCst) {
+            0 => HttpResponse::ServiceUnavailable().finish(),
+            1 => HttpResponse::TooManyRequests()
+                .insert_header(("Retry-After", "0"))
+                .finish(),
+            _ => HttpResponse::Ok().body("ok"),
+        }
+    }
+
+    async fn missing(hits: web::Data<AtomicUsize>) -> HttpResponse {
+        hits.fetch_add(1, Ordering::SeqCst);
+        HttpResponse::NotFound().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_retry() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let srv = {
+            let hits = hits.clone();
+            actix_test::start(move || {
+                App::new()
+                    .app_data(web::Data::from(hits.clone()))
+                    .route("/flaky", web::get().to(flaky))
+                    .route("/missing", web::get().to(missing))
+            })
+        };
+        let retry = Retry::new(3, Duration::from_millis(1));
+
+        let mut res = retry
+            .send(Client::default().get(srv.url("/flaky")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body().await.unwrap(), "ok");
+        assert_eq!(hits.swap(0, Ordering::SeqCst), 3);
+
+        let res = retry
+            .send(Client::default().get(srv.url("/missing")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_breaker() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let srv = {
+            let hits = hits.clone();
+            actix_test::start(move || {
+                App::new()
+                    .app_data(web::Data::from(hits.clone()))
+                    .route("/flaky", web::get().to(flaky))
+            })
+        };
+        let retry = Retry::new(1, Duration::from_millis(1));
+        let breaker = Breaker::new(2, Duration::from_secs(60), Duration::from_millis(50));
+        let send = || retry.send(Client::default().get(srv.url("/flaky")));
+
+        // 503 then 429 open circuit
+        breaker.call(send()).await.unwrap();
+        assert_eq!(breaker.status(), BreakerStatus::Closed);
+        breaker.call(send()).await.unwrap();
+        assert_eq!(breaker.status(), BreakerStatus::Open);
+
+        let err = breaker.call(send()).await.unwrap_err();
+        assert!(matches!(err, Error::Upstream(Upstream::Unavailable)));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // cancelled probe does not keep circuit open
+        actix_web::rt::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(breaker.status(), BreakerStatus::HalfOpen);
+        let pending = futures::future::pending::<Result<Response, Error>>();
+        let cancelled =
+            actix_web::rt::time::timeout(Duration::from_millis(10), breaker.call(pending)).await;
+        assert!(cancelled.is_err());
+
+        // probe after cooldown succeeds and closes circuit
+        assert_eq!(breaker.status(), BreakerStatus::HalfOpen);
+        let mut res = breaker.call(send()).await.unwrap();
+        assert_eq!(res.body().await.unwrap(), "ok");
+        assert_eq!(breaker.status(), BreakerStatus::Closed);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_agent() {
+        assert!(Agent::new("libres-test/1.0").is_ok());
+
+        // header value with line break is refused at startup
+        let err = Agent::new("libres\r\nX-Injected: 1").unwrap_err();
+        assert!(matches!(err, Error::Config(_)));
+        let err = Agent::default()
+            .with_contact("ops@example.com\n")
+            .unwrap_err();
+        assert!(matches!(err, Error::Config(_)));
+    }
+
+    #[test]
+    fn test_upstream_request() {
+        let request = Client::default()
+            .get("https://api.calil.jp/check")
+            .query(&[("appkey", "secret"), ("isbn", "9784001141276")])
+            .unwrap();
+        let res = UpstreamRequest::new(&request);
+
+        assert_eq!(res.method, "GET");
+        assert_eq!(res.url, "https://api.calil.jp/check");
+        let params: Vec<_> = res
+            .params
+            .iter()
+            .map(|param| (param.name.as_str(), param.value.as_str()))
+            .collect();
+        assert_eq!(params, vec![("appkey", "***"), ("isbn", "9784001141276")]);
+    }
+
+    #[actix_web::test]
+    async fn test_limiter() {
+        let limiter = Limiter::new(2, Duration::from_secs(5));
+        let current = Cell::new(0);
+        let peak = Cell::new(0);
+
+        let tasks = (0..8).map(|_| async {
+            let _permit = limiter.acquire().await.unwrap();
+            current.set(current.get() + 1);
+            peak.set(peak.get().max(current.get()));
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+            current.set(current.get() - 1);
+        });
+        futures::future::join_all(tasks).await;
+
+        assert_eq!(peak.get(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_limiter_busy() {
+        let limiter = Limiter::new(1, Duration::from_millis(10));
+
+        let _permit = limiter.acquire().await.unwrap();
+        let err = limiter.acquire().await.unwrap_err();
+        assert!(matches!(err, Error::Upstream(Upstream::Busy)));
+    }
+
+    #[actix_web::test]
+    async fn test_image_exists() {
+        let hits = web::Data::new(AtomicUsize::new(0));
+        let srv = actix_test::start({
+            let hits = hits.clone();
+            move || {
+                App::new()
+                    .app_data(hits.clone())
+                    .route(
+                        "/cover.jpg",
+                        web::head().to(|hits: web::Data<AtomicUsize>| async move {
+                            hits.fetch_add(1, Ordering::SeqCst);
+                            HttpResponse::Ok()
+                                .content_type("image/jpeg")
+                                .body(vec![0; 2048])
+                        }),
+                    )
+                    .route(
+                        "/placeholder.gif",
+                        web::head().to(|| async {
+                            HttpResponse::Ok()
+                                .content_type("image/gif")
+                                .body(vec![0; 43])
+                        }),
+                    )
+                    .route(
+                        "/missing.jpg",
+                        web::head().to(|hits: web::Data<AtomicUsize>| async move {
+                            hits.fetch_add(1, Ordering::SeqCst);
+                            HttpResponse::NotFound().finish()
+                        }),
+                    )
+                    .route(
+                        "/error.html",
+                        web::head()
+                            .to(|| async { HttpResponse::Ok().content_type("text/html").finish() }),
+                    )
+                    // head is not allowed
+                    .service(web::resource("/ranged.jpg").route(web::get().to(|| async {
+                        HttpResponse::PartialContent()
+                            .content_type("image/jpeg")
+                            .insert_header((header::CONTENT_RANGE, "bytes 0-0/2048"))
+                            .body("j")
+                    })))
+            }
+        });
+        let url = |path: &str| format!("http://{}{path}", srv.addr());
+        let agent = Agent::default();
+
+        assert!(image_exists(&agent, &url("/cover.jpg")).await);
+        assert!(image_exists(&agent, &url("/ranged.jpg")).await);
+        assert!(!image_exists(&agent, &url("/error.html")).await);
+        assert!(!image_exists(&agent, &url("/placeholder.gif")).await);
+
+        // found and missing image are asked once while cached
+        assert!(image_exists(&agent, &url("/cover.jpg")).await);
+        assert!(!image_exists(&agent, &url("/missing.jpg")).await);
+        assert!(!image_exists(&agent, &url("/missing.jpg")).await);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,155 @@
+use actix_web::HttpResponse;
+use serde::Serialize;
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+// columns are VARCHAR(255)
+pub const MAX_FIELD_LEN: usize = 255;
+
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+// request body limit of json and authenticated endpoints
+pub const MAX_BODY_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+// per-field messages returned as 400 body
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ValidationErrors {
+    pub errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn non_empty(&mut self, field: &'static str, value: &str) -> &mut Self {
+        if value.trim().is_empty() {
+            self.push(field, "must not be empty".to_string());
+        }
+        self
+    }
+
+    pub fn min_len(&mut self, field: &'static str, value: &str, min: usize) -> &mut Self {
+        if value.chars().count() < min {
+            self.push(field, format!("must be at least {min} characters"));
+        }
+        self
+    }
+
+    pub fn max_len(&mut self, field: &'static str, value: &str, max: usize) -> &mut Self {
+        if value.chars().count() > max {
+            self.push(field, format!("must be at most {max} characters"));
+        }
+        self
+    }
+
+    pub fn range(&mut self, field: &'static str, value: u32, min: u32, max: u32) -> &mut Self {
+        if value < min || value > max {
+            self.push(field, format!("must be between {min} and {max}"));
+        }
+        self
+    }
+
+    // degrees, nan and infinity are out of range too
+    pub fn latitude(&mut self, field: &'static str, value: f64) -> &mut Self {
+        if !(-90.0..=90.0).contains(&value) {
+            self.push(field, "must be between -90 and 90".to_string());
+        }
+        self
+    }
+
+    pub fn longitude(&mut self, field: &'static str, value: f64) -> &mut Self {
+        if !(-180.0..=180.0).contains(&value) {
+            self.push(field, "must be between -180 and 180".to_string());
+        }
+        self
+    }
+
+    pub fn email(&mut self, field: &'static str, value: &str) -> &mut Self {
+        let valid = value
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty());
+        if !valid {
+            self.push(field, "must be an email address".to_string());
+        }
+        self
+    }
+
+    pub fn into_result(self) -> Result<(), Self> {
+        match self.errors.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
+
+    fn push(&mut self, field: &'static str, message: String) {
+        self.errors.push(FieldError { field, message });
+    }
+}
+
+// 413 in the same shape as validation errors
+pub fn body_too_large() -> HttpResponse {
+    let errors = ValidationErrors {
+        errors: vec![FieldError {
+            field: "body",
+            message: format!("must be at most {MAX_BODY_SIZE} bytes"),
+        }],
+    };
+    HttpResponse::PayloadTooLarge().json(errors)
+}
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationErrors>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::ValidationErrors;
+
+    #[test]
+    fn test_validation_errors() {
+        let mut errors = ValidationErrors::default();
+        errors
+            .non_empty("fullname", " ")
+            .min_len("password", "short", 8)
+            .max_len("address", &"a".repeat(256), 255)
+            .email("email", "alice")
+            .range("page_size", 4000000000, 1, 100);
+        let fields: Vec<_> = errors.errors.iter().map(|error| error.field).collect();
+        assert_eq!(
+            fields,
+            ["fullname", "password", "address", "email", "page_size"]
+        );
+        assert!(errors.into_result().is_err());
+
+        // length counts characters, not bytes
+        let mut errors = ValidationErrors::default();
+        errors
+            .non_empty("fullname", "アリス")
+            .max_len("fullname", "アリス", 3)
+            .email("email", "alice@example.com")
+            .range("page_size", 100, 1, 100);
+        assert!(errors.into_result().is_ok());
+    }
+
+    #[test]
+    fn test_validation_geocode() {
+        let mut errors = ValidationErrors::default();
+        errors
+            .latitude("latitude", 500.0)
+            .latitude("latitude", f64::NAN)
+            .longitude("longitude", -180.5)
+            .longitude("longitude", f64::INFINITY);
+        assert_eq!(errors.errors.len(), 4);
+
+        let mut errors = ValidationErrors::default();
+        errors
+            .latitude("latitude", -90.0)
+            .latitude("latitude", 36.7077262)
+            .longitude("longitude", 180.0)
+            .longitude("longitude", 137.0958753);
+        assert!(errors.into_result().is_ok());
+    }
+}